@@ -0,0 +1,112 @@
+use core::cmp;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use libc_print::libc_eprintln;
+
+use crate::alloc::heap::Heap;
+
+/// Maximum number of arenas a single `Collam` instance can manage.
+pub const ARENA_LIMIT: usize = 32;
+
+lazy_static! {
+    /// Number of arenas used if no maximum has been set: two per online processor.
+    static ref ARENA_MAX_DEFAULT: usize = {
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+        cmp::min(cmp::max(cpus, 1) as usize * 2, ARENA_LIMIT)
+    };
+}
+
+/// Binding of the current thread as (instance id, arena index), id 0 if unbound.
+#[thread_local]
+static mut THREAD_ARENA: (usize, usize) = (0, 0);
+
+/// Independent heaps, each with its own lock, free bins and memory source.
+/// Threads are bound to the arenas in round-robin order.
+pub struct Arenas {
+    heaps: [spin::Mutex<Heap>; ARENA_LIMIT],
+    /// Number of bindings so far, selects the arena of the next thread.
+    next: AtomicUsize,
+}
+
+impl Arenas {
+    pub const fn new() -> Self {
+        let mut heaps = [const { spin::Mutex::new(Heap::new(0)) }; ARENA_LIMIT];
+        let mut idx = 1;
+        while idx < ARENA_LIMIT {
+            heaps[idx] = spin::Mutex::new(Heap::new(idx));
+            idx += 1;
+        }
+        Arenas {
+            heaps,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the arena with the given index or `None` if it does not exist.
+    #[inline]
+    pub fn get(&self, idx: usize) -> Option<&spin::Mutex<Heap>> {
+        self.heaps.get(idx)
+    }
+
+    /// Returns the index of the arena the current thread is bound to for the
+    /// instance with the given id. Unbound threads are bound to one of the first
+    /// `max` arenas.
+    pub fn current(&self, id: usize, max: usize) -> usize {
+        debug_assert!(max > 0 && max <= ARENA_LIMIT);
+        let binding = unsafe { &mut *ptr::addr_of_mut!(THREAD_ARENA) };
+        // Rebind if the maximum has been lowered in the meantime.
+        if binding.0 != id || binding.1 >= max {
+            let idx = self.next.fetch_add(1, Ordering::Relaxed) % max;
+            dprintln!("[arena]: binding thread to arena {}", idx);
+            *binding = (id, idx);
+        }
+        binding.1
+    }
+}
+
+/// Returns the number of arenas used if no maximum has been configured.
+#[inline]
+pub fn max_default() -> usize {
+    *ARENA_MAX_DEFAULT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arenas_new() {
+        let arenas = Arenas::new();
+        for idx in 0..ARENA_LIMIT {
+            let heap = arenas.get(idx).expect("missing arena").lock();
+            assert_eq!(heap.arena(), idx);
+        }
+        assert!(arenas.get(ARENA_LIMIT).is_none());
+    }
+
+    #[test]
+    fn test_max_default() {
+        assert!(max_default() >= 2 && max_default() <= ARENA_LIMIT);
+    }
+
+    #[test]
+    fn test_arenas_current() {
+        let arenas = Arenas::new();
+        let idx = arenas.current(usize::MAX, 4);
+        assert_eq!(arenas.current(usize::MAX, 4), idx);
+
+        // Other threads are bound round-robin.
+        let others: std::vec::Vec<usize> = (0..4)
+            .map(|_| {
+                let arenas = &arenas;
+                std::thread::scope(|s| s.spawn(move || arenas.current(usize::MAX, 4)).join())
+                    .expect("thread panicked")
+            })
+            .collect();
+        assert_eq!(others, [1, 2, 3, 0]);
+
+        // Lowering the maximum rebinds the thread.
+        assert_eq!(arenas.current(usize::MAX, 1), 0);
+    }
+}
@@ -0,0 +1,307 @@
+use core::{cmp, mem};
+
+use libc_print::libc_eprintln;
+
+use crate::alloc::block::BlockPtr;
+use crate::alloc::info::SizeClass;
+use crate::alloc::list::IntrusiveList;
+
+/// Size difference between two neighbouring small bins.
+const BIN_GRANULARITY: usize = mem::align_of::<libc::max_align_t>();
+/// Number of bins holding blocks of one exact size each.
+const SMALL_BINS: usize = 64;
+/// Blocks of this size and above are kept in large bins.
+const LARGE_MIN_SIZE: usize = SMALL_BINS * BIN_GRANULARITY;
+/// Number of large bins each power of two is divided into.
+const LARGE_BINS_PER_POW: usize = 4;
+/// Total number of bins, the last bin holds all blocks exceeding the other bins.
+pub const NUM_BINS: usize = 128;
+
+/// Segregated free lists: exact size bins for small blocks and
+/// logarithmic bins for large blocks, plus a bitmap of non-empty bins.
+pub struct Bins {
+    lists: [IntrusiveList; NUM_BINS],
+    /// Bit `i` is set if `lists[i]` contains at least one block.
+    bitmap: u128,
+    /// Number of blocks in all bins.
+    count: usize,
+    /// Sum of the sizes of all blocks in all bins.
+    bytes: usize,
+}
+
+impl Bins {
+    pub const fn new() -> Self {
+        const EMPTY: IntrusiveList = IntrusiveList::new();
+        Bins {
+            lists: [EMPTY; NUM_BINS],
+            bitmap: 0,
+            count: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Adds a free `BlockPtr` to its bin and marks it free.
+    pub unsafe fn insert(&mut self, mut block: BlockPtr) {
+        debug_assert!(!block.is_free());
+        let idx = bin_index(block.size());
+        block.set_free(true);
+        self.lists[idx].insert(block);
+        self.bitmap |= 1 << idx;
+        self.count += 1;
+        self.bytes += block.size();
+    }
+
+    /// Removes the given free `BlockPtr` from its bin and marks it in use.
+    pub unsafe fn remove(&mut self, block: BlockPtr) -> BlockPtr {
+        debug_assert!(block.is_free());
+        let idx = bin_index(block.size());
+        let block = self.lists[idx].remove(block);
+        self.taken(idx, block)
+    }
+
+    /// Removes and returns a `BlockPtr` with at least the given size.
+    /// Exact size bins are served in constant time, large bins are only
+    /// scanned for the bin `size` belongs to, higher bins fit any block.
+    pub fn pop(&mut self, size: usize) -> Option<BlockPtr> {
+        let idx = bin_index(size);
+        if self.bitmap & (1 << idx) != 0 {
+            if let Some(block) = self.lists[idx].pop(size) {
+                return Some(self.taken(idx, block));
+            }
+        }
+
+        // Any block in a higher bin is large enough.
+        let higher = self.bitmap & !(u128::MAX >> (NUM_BINS - 1 - idx));
+        if higher == 0 {
+            return None;
+        }
+        let idx = higher.trailing_zeros() as usize;
+        let head = self.lists[idx].head?;
+        let block = unsafe { self.lists[idx].remove(head) };
+        debug_assert!(block.size() >= size);
+        Some(self.taken(idx, block))
+    }
+
+    /// Returns the number of free blocks.
+    #[inline]
+    #[allow(unused)]
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the sum of the sizes of all free blocks.
+    #[inline]
+    #[allow(unused)]
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Returns an iterator over all free blocks ordered by bin.
+    #[allow(unused)]
+    pub fn iter(&self) -> impl Iterator<Item = BlockPtr> + '_ {
+        self.lists.iter().flat_map(|list| list.iter())
+    }
+
+    /// Walks all bins and returns the sizes of the free blocks in each of them.
+    pub fn size_classes(&self) -> [SizeClass; NUM_BINS] {
+        let mut classes = [SizeClass::default(); NUM_BINS];
+        for (class, list) in classes.iter_mut().zip(self.lists.iter()) {
+            *class = list.size_class();
+        }
+        classes
+    }
+
+    /// Prints some debugging information about the bins.
+    #[cfg(feature = "debug")]
+    pub fn debug(&self) {
+        dprintln!("[debug]: === bins debug start ===");
+        for (idx, list) in self.lists.iter().enumerate() {
+            debug_assert_eq!(self.bitmap & (1 << idx) != 0, !list.is_empty());
+            if list.is_empty() {
+                continue;
+            }
+            dprintln!("[debug]: bin {}", idx);
+            list.debug();
+            for block in list.iter() {
+                debug_assert_eq!(bin_index(block.size()), idx);
+            }
+        }
+        debug_assert_eq!(self.iter().count(), self.count);
+        debug_assert_eq!(self.iter().map(|b| b.size()).sum::<usize>(), self.bytes);
+        dprintln!("[debug]: === bins debug end ===");
+    }
+
+    /// Updates bookkeeping for a block removed from bin `idx`.
+    #[inline]
+    fn taken(&mut self, idx: usize, mut block: BlockPtr) -> BlockPtr {
+        if self.lists[idx].is_empty() {
+            self.bitmap &= !(1 << idx);
+        }
+        self.count -= 1;
+        self.bytes -= block.size();
+        block.set_free(false);
+        dprintln!(
+            "[bins]: took {} at {:p} from bin {}",
+            block.as_ref(),
+            block,
+            idx
+        );
+        block
+    }
+}
+
+/// Returns the bin index for blocks of the given size.
+#[inline]
+pub fn bin_index(size: usize) -> usize {
+    if size < LARGE_MIN_SIZE {
+        return size / BIN_GRANULARITY;
+    }
+    // Divide each power of two into `LARGE_BINS_PER_POW` bins.
+    let log = (usize::BITS - 1 - size.leading_zeros()) as usize;
+    let sub =
+        (size >> (log - LARGE_BINS_PER_POW.trailing_zeros() as usize)) & (LARGE_BINS_PER_POW - 1);
+    let base = log - LARGE_MIN_SIZE.trailing_zeros() as usize;
+    cmp::min(SMALL_BINS + base * LARGE_BINS_PER_POW + sub, NUM_BINS - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::block::BLOCK_META_SIZE;
+    use core::ffi::c_void;
+    use core::ptr::Unique;
+
+    /// Creates blocks of the given sizes followed by a fence post within a heap allocated buffer.
+    fn with_blocks<F: FnOnce(&[BlockPtr])>(sizes: &[usize], f: F) {
+        let total: usize =
+            sizes.iter().map(|s| s + BLOCK_META_SIZE).sum::<usize>() + BLOCK_META_SIZE;
+        let buf = unsafe { libc::malloc(total) };
+        assert!(!buf.is_null());
+        let mut blocks = std::vec::Vec::new();
+        let mut ptr = buf;
+        for size in sizes.iter() {
+            let block = BlockPtr::new(unsafe { Unique::new_unchecked(ptr) }, *size);
+            ptr = block.next_potential_block().as_ptr();
+            blocks.push(block);
+        }
+        BlockPtr::new_fence(unsafe { Unique::new_unchecked(ptr) });
+        f(&blocks);
+        unsafe { libc::free(buf.cast::<c_void>()) };
+    }
+
+    #[test]
+    fn test_bin_index() {
+        assert_eq!(bin_index(16), 1);
+        assert_eq!(bin_index(32), 2);
+        assert_eq!(bin_index(LARGE_MIN_SIZE - 16), SMALL_BINS - 1);
+        assert_eq!(bin_index(LARGE_MIN_SIZE), SMALL_BINS);
+        assert_eq!(bin_index(LARGE_MIN_SIZE + 256), SMALL_BINS + 1);
+        assert_eq!(
+            bin_index(LARGE_MIN_SIZE * 2),
+            SMALL_BINS + LARGE_BINS_PER_POW
+        );
+        assert_eq!(bin_index(usize::MAX), NUM_BINS - 1);
+
+        // Bin indices must grow monotonically with the size.
+        let mut last = 0;
+        for size in (16..1 << 24).step_by(16) {
+            let idx = bin_index(size);
+            assert!(idx >= last, "size={}", size);
+            last = idx;
+        }
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        with_blocks(&[64, 2048], |blocks| unsafe {
+            let mut bins = Bins::new();
+            bins.insert(blocks[0]);
+            bins.insert(blocks[1]);
+            assert!(blocks[0].is_free() && blocks[1].is_free());
+            assert_eq!(bins.count(), 2);
+            assert_eq!(bins.bytes(), 64 + 2048);
+            assert_eq!(bins.iter().count(), 2);
+
+            let block = bins.remove(blocks[1]);
+            assert!(!block.is_free());
+            assert_eq!(bins.count(), 1);
+            assert_eq!(bins.bytes(), 64);
+            assert_eq!(bins.bitmap, 1 << bin_index(64));
+        });
+    }
+
+    #[test]
+    fn test_pop_exact_size() {
+        with_blocks(&[64, 128, 64], |blocks| unsafe {
+            let mut bins = Bins::new();
+            for block in blocks.iter() {
+                bins.insert(*block);
+            }
+            assert_eq!(bins.pop(128), Some(blocks[1]));
+            assert_eq!(bins.pop(64), Some(blocks[0]));
+            assert_eq!(bins.pop(64), Some(blocks[2]));
+            assert_eq!(bins.pop(64), None);
+            assert_eq!(bins.bitmap, 0);
+            assert_eq!(bins.count(), 0);
+        });
+    }
+
+    #[test]
+    fn test_size_classes() {
+        with_blocks(&[1024, 64, 1200, 64], |blocks| unsafe {
+            let mut bins = Bins::new();
+            for block in blocks.iter() {
+                bins.insert(*block);
+            }
+            let classes = bins.size_classes();
+            let class = classes[bin_index(64)];
+            assert_eq!(
+                (class.from, class.to, class.total, class.count),
+                (64, 64, 128, 2)
+            );
+            let class = classes[bin_index(1024)];
+            assert_eq!(
+                (class.from, class.to, class.total, class.count),
+                (1024, 1200, 2224, 2)
+            );
+            let total: usize = classes.iter().map(|c| c.count).sum();
+            assert_eq!(total, bins.count());
+        });
+    }
+
+    #[test]
+    fn test_pop_higher_bin() {
+        with_blocks(&[64, 4096], |blocks| unsafe {
+            let mut bins = Bins::new();
+            bins.insert(blocks[0]);
+            bins.insert(blocks[1]);
+            assert_eq!(bins.pop(32), Some(blocks[0]));
+            assert_eq!(bins.pop(80), Some(blocks[1]));
+            assert_eq!(bins.pop(16), None);
+        });
+    }
+
+    #[test]
+    fn test_pop_large_bin_scan() {
+        // Both blocks share a bin, but only the second one is large enough.
+        with_blocks(&[1024, 1200], |blocks| unsafe {
+            assert_eq!(bin_index(1024), bin_index(1200));
+            let mut bins = Bins::new();
+            bins.insert(blocks[0]);
+            bins.insert(blocks[1]);
+            assert_eq!(bins.pop(1100), Some(blocks[1]));
+            assert_eq!(bins.pop(1100), None);
+            assert_eq!(bins.pop(1000), Some(blocks[0]));
+        });
+    }
+
+    #[test]
+    fn test_pop_last_bin() {
+        with_blocks(&[1 << 30], |blocks| unsafe {
+            let mut bins = Bins::new();
+            bins.insert(blocks[0]);
+            assert_eq!(bins.pop((1 << 30) + 16), None);
+            assert_eq!(bins.pop(1 << 29), Some(blocks[0]));
+        });
+    }
+}
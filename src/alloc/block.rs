@@ -1,20 +1,84 @@
-use core::{ffi::c_void, fmt, intrinsics, mem, ptr::Unique};
+use core::{cmp, ffi::c_void, fmt, intrinsics, mem, ptr::Unique};
 
 use libc_print::libc_eprintln;
 
-use crate::util;
+use crate::alloc::PAGE_SIZE;
+use crate::{stats, util};
 
 /// The required block size to store the bare minimum of metadata (size + magic values).
 pub const BLOCK_META_SIZE: usize = util::align_scalar_unchecked(mem::align_of::<usize>() * 2);
-/// The minimum region size to save intrusive data structures if not allocated by the user.
-const BLOCK_MIN_REGION_SIZE: usize =
-    util::align_scalar_unchecked(mem::align_of::<Option<BlockPtr>>() * 2);
+/// The minimum region size to save intrusive data structures and the footer
+/// if not allocated by the user.
+pub const BLOCK_MIN_REGION_SIZE: usize =
+    util::align_scalar_unchecked(mem::size_of::<Option<BlockPtr>>() * 2 + mem::size_of::<usize>());
+/// Size of the intrusive list links at the start of the memory region of free blocks.
+pub const BLOCK_LINKS_SIZE: usize = mem::size_of::<Option<BlockPtr>>() * 2;
 /// Defines the minimum remaining size of a block to consider splitting it.
 pub const BLOCK_SPLIT_MIN_SIZE: usize = util::align_scalar_unchecked(
     BLOCK_META_SIZE + BLOCK_MIN_REGION_SIZE + mem::align_of::<libc::max_align_t>(),
 );
 
+/// Magic values encoding the `BlockState` in the block header.
+const BLOCK_MAGIC_ALLOCATED: u16 = 0xA110;
 const BLOCK_MAGIC_FREE: u16 = 0xDEAD;
+const BLOCK_MAGIC_MMAPPED: u16 = 0x3A9D;
+const BLOCK_MAGIC_QUARANTINED: u16 = 0x0BAD;
+const BLOCK_MAGIC_FENCE: u16 = 0xFE4C;
+
+/// Set if the block has been allocated with its own `mmap` call.
+const BLOCK_FLAG_MMAPPED: u16 = 1;
+/// Set if the block is currently part of the free bins.
+const BLOCK_FLAG_FREE: u16 = 1 << 1;
+/// Set if the block is a fence post marking the end of a heap segment.
+const BLOCK_FLAG_FENCE: u16 = 1 << 2;
+/// Set if the physically preceding block is free and its footer holds its size.
+const BLOCK_FLAG_PREV_FREE: u16 = 1 << 3;
+/// Set if the block is held by a thread cache.
+const BLOCK_FLAG_CACHED: u16 = 1 << 4;
+/// Set if the allocation has been recorded by the heap profiler.
+const BLOCK_FLAG_SAMPLED: u16 = 1 << 5;
+/// Set if the mapping of a mmapped block ends with an inaccessible guard page,
+/// which directly follows the memory region.
+const BLOCK_FLAG_GUARDED: u16 = 1 << 6;
+
+/// State of a block, which is stored as magic value in its header
+/// and can be checked in O(1) for pointers passed by the user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockState {
+    /// Heap block in use.
+    Allocated,
+    /// Heap block in the free bins or a thread cache.
+    Free,
+    /// Block in use with a dedicated mapping.
+    Mmapped,
+    /// Freed heap block withheld from reuse.
+    Quarantined,
+    /// Fence post marking the end of a heap segment.
+    Fence,
+}
+
+impl BlockState {
+    const fn magic(self) -> u16 {
+        match self {
+            BlockState::Allocated => BLOCK_MAGIC_ALLOCATED,
+            BlockState::Free => BLOCK_MAGIC_FREE,
+            BlockState::Mmapped => BLOCK_MAGIC_MMAPPED,
+            BlockState::Quarantined => BLOCK_MAGIC_QUARANTINED,
+            BlockState::Fence => BLOCK_MAGIC_FENCE,
+        }
+    }
+
+    const fn from_magic(magic: u16) -> Option<BlockState> {
+        match magic {
+            BLOCK_MAGIC_ALLOCATED => Some(BlockState::Allocated),
+            BLOCK_MAGIC_FREE => Some(BlockState::Free),
+            BLOCK_MAGIC_MMAPPED => Some(BlockState::Mmapped),
+            BLOCK_MAGIC_QUARANTINED => Some(BlockState::Quarantined),
+            BLOCK_MAGIC_FENCE => Some(BlockState::Fence),
+            _ => None,
+        }
+    }
+}
 
 /// Represents a mutable non-null Pointer to a `Block`.
 #[repr(C)]
@@ -30,6 +94,52 @@ impl BlockPtr {
         BlockPtr(ptr)
     }
 
+    /// Creates a `Block` for a dedicated mapping at the given raw pointer for the specified size.
+    /// `offset` is the distance from the start of the mapping to the block.
+    pub fn new_mmapped(ptr: Unique<c_void>, size: usize, offset: u32) -> Self {
+        let mut block = BlockPtr::new(ptr, size);
+        block.as_mut().flags = BLOCK_FLAG_MMAPPED;
+        block.as_mut().magic = BLOCK_MAGIC_MMAPPED;
+        block.as_mut().tag = offset;
+        block
+    }
+
+    /// Creates a `Block` for a dedicated mapping like `new_mmapped`, whose memory region
+    /// is followed by a guard page at the end of the mapping.
+    /// The size only needs to be a multiple of the alignment of a `Block`,
+    /// since the memory region ends right at the guard page.
+    pub fn new_guarded(ptr: Unique<c_void>, size: usize, offset: u32) -> Self {
+        debug_assert_eq!(size % mem::align_of::<Block>(), 0);
+        let padded = util::pad_to_scalar(size).unwrap().size();
+        let mut block = BlockPtr::new_mmapped(ptr, padded, offset);
+        block.as_mut().size = size;
+        block.as_mut().flags |= BLOCK_FLAG_GUARDED;
+        block
+    }
+
+    /// Creates a fence post at the given raw pointer, which marks the end of a heap segment.
+    /// Only the metadata is written, so the pointer may be `BLOCK_META_SIZE` bytes
+    /// before the end of accessible memory.
+    pub fn new_fence(ptr: Unique<c_void>) -> Self {
+        let block = ptr.cast::<Block>().as_ptr();
+        unsafe {
+            (*block).size = 0;
+            (*block).magic = BLOCK_MAGIC_FENCE;
+            (*block).flags = BLOCK_FLAG_FENCE;
+            (*block).tag = 0;
+        }
+        BlockPtr(unsafe { Unique::new_unchecked(block) })
+    }
+
+    /// Returns the `BlockPtr` of a mmapped block which has been moved to the given mapping
+    /// and updates its size. Unlike `new_mmapped` the memory region is left untouched.
+    pub fn remapped(ptr: Unique<c_void>, size: usize) -> Self {
+        let mut block = BlockPtr(ptr.cast::<Block>());
+        debug_assert!(block.as_ref().verify() && block.is_mmapped() && block.offset() == 0);
+        block.as_mut().size = size;
+        block
+    }
+
     /// Returns an existing `BlockPtr` instance from the given memory region raw pointer
     pub fn from_mem_region(ptr: Unique<c_void>) -> Option<Self> {
         let block_ptr = unsafe { ptr.as_ptr().sub(BLOCK_META_SIZE).cast::<Block>() };
@@ -60,6 +170,54 @@ impl BlockPtr {
         unsafe { Unique::new_unchecked(self.cast::<c_void>().as_ptr().add(self.block_size())) }
     }
 
+    /// Returns the block physically following this block.
+    /// NOTE: Only valid for heap blocks, since each heap segment is terminated by a fence post.
+    #[inline]
+    pub fn next_block(self) -> BlockPtr {
+        debug_assert!(!self.is_mmapped() && !self.is_fence());
+        BlockPtr(self.next_potential_block().cast::<Block>())
+    }
+
+    /// Returns the block physically preceding this block, if it is free.
+    /// The predecessor is looked up through the footer at the end of its region.
+    #[inline]
+    pub fn prev_free_block(self) -> Option<BlockPtr> {
+        if !self.is_prev_free() {
+            return None;
+        }
+        let block = unsafe { self.prev_block_unchecked() };
+        debug_assert!(block.as_ref().verify() && block.is_free());
+        debug_assert_eq!(block.next_block(), self);
+        Some(block)
+    }
+
+    /// Returns the block physically preceding this block as given by the footer
+    /// before this block, which is only written for free blocks.
+    /// NOTE: The footer is trusted, so the state of the result must be checked.
+    #[inline]
+    pub(crate) unsafe fn prev_block_unchecked(self) -> BlockPtr {
+        let size = *self.cast::<usize>().as_ptr().sub(1);
+        let ptr = self
+            .cast::<u8>()
+            .as_ptr()
+            .wrapping_sub(BLOCK_META_SIZE + size);
+        BlockPtr(Unique::new_unchecked(ptr.cast::<Block>()))
+    }
+
+    /// Fills the memory region with `byte`, apart from the intrusive list links
+    /// at its start, so the block can be filled while being part of a list.
+    pub fn fill_unlinked(self, byte: u8) {
+        debug_assert!(self.size() >= BLOCK_LINKS_SIZE);
+        unsafe {
+            let region = self.mem_region().cast::<u8>().as_ptr();
+            intrinsics::volatile_set_memory(
+                region.add(BLOCK_LINKS_SIZE),
+                byte,
+                self.size() - BLOCK_LINKS_SIZE,
+            );
+        }
+    }
+
     /// Returns the allocatable size available for the user
     #[inline]
     pub fn size(&self) -> usize {
@@ -72,42 +230,175 @@ impl BlockPtr {
         BLOCK_META_SIZE + self.size()
     }
 
-    /// Tries to merge self with the next block, if available.
-    /// Returns a merged `BlockPtr` if merge was possible, `None` otherwise.
-    pub fn maybe_merge_next(mut self) -> Option<BlockPtr> {
-        let next = self.as_ref().next?;
+    /// Returns `true` if the block has its own mapping.
+    #[inline]
+    pub fn is_mmapped(&self) -> bool {
+        self.as_ref().flags & BLOCK_FLAG_MMAPPED != 0
+    }
 
-        if self.next_potential_block().as_ptr() != next.cast::<c_void>().as_ptr() {
-            return None;
+    /// Returns `true` if the memory region of the mmapped block is followed by a guard page.
+    #[inline]
+    pub fn is_guarded(&self) -> bool {
+        self.as_ref().flags & BLOCK_FLAG_GUARDED != 0
+    }
+
+    /// Returns `true` if the block is currently part of the free bins.
+    #[inline]
+    pub fn is_free(&self) -> bool {
+        self.as_ref().flags & BLOCK_FLAG_FREE != 0
+    }
+
+    /// Marks the block as part of the free bins or as in use.
+    /// Free blocks store their size in a footer and announce themselves
+    /// to their physical successor, which must exist.
+    #[inline]
+    pub fn set_free(&mut self, free: bool) {
+        let mut next = self.next_block();
+        if free {
+            debug_assert!(self.size() >= BLOCK_MIN_REGION_SIZE);
+            self.as_mut().flags |= BLOCK_FLAG_FREE;
+            self.set_state(BlockState::Free);
+            unsafe { *self.footer().as_ptr() = self.size() };
+            next.as_mut().flags |= BLOCK_FLAG_PREV_FREE;
+        } else {
+            self.as_mut().flags &= !BLOCK_FLAG_FREE;
+            self.set_state(BlockState::Allocated);
+            next.as_mut().flags &= !BLOCK_FLAG_PREV_FREE;
+        }
+    }
+
+    /// Returns `true` if the block is held by a thread cache.
+    #[inline]
+    pub fn is_cached(&self) -> bool {
+        self.as_ref().flags & BLOCK_FLAG_CACHED != 0
+    }
+
+    /// Marks the block as held by a thread cache or as in use.
+    #[inline]
+    pub fn set_cached(&mut self, cached: bool) {
+        if cached {
+            self.as_mut().flags |= BLOCK_FLAG_CACHED;
+            self.set_state(BlockState::Free);
+        } else {
+            self.as_mut().flags &= !BLOCK_FLAG_CACHED;
+            self.set_state(BlockState::Allocated);
         }
+    }
 
+    /// Returns the state of the block or `None` if the header is corrupted
+    /// or the pointer does not point to a block at all.
+    #[inline]
+    pub fn state(&self) -> Option<BlockState> {
+        BlockState::from_magic(self.as_ref().magic)
+    }
+
+    /// Sets the state of the block. Flags are left to the caller.
+    #[inline]
+    pub fn set_state(&mut self, state: BlockState) {
+        self.as_mut().magic = state.magic();
+    }
+
+    /// Returns `true` if the allocation has been recorded by the heap profiler.
+    #[inline]
+    pub fn is_sampled(&self) -> bool {
+        self.as_ref().flags & BLOCK_FLAG_SAMPLED != 0
+    }
+
+    /// Marks the allocation as recorded by the heap profiler.
+    #[inline]
+    pub fn set_sampled(&mut self, sampled: bool) {
+        if sampled {
+            self.as_mut().flags |= BLOCK_FLAG_SAMPLED;
+        } else {
+            self.as_mut().flags &= !BLOCK_FLAG_SAMPLED;
+        }
+    }
+
+    /// Returns `true` if the physically preceding block is free.
+    #[inline]
+    pub fn is_prev_free(&self) -> bool {
+        self.as_ref().flags & BLOCK_FLAG_PREV_FREE != 0
+    }
+
+    /// Returns a pointer to the footer, which is the last word of the memory region.
+    #[inline]
+    fn footer(self) -> Unique<usize> {
+        unsafe {
+            Unique::new_unchecked(self.next_potential_block().cast::<usize>().as_ptr().sub(1))
+        }
+    }
+
+    /// Returns `true` if the block is a fence post.
+    #[inline]
+    pub fn is_fence(&self) -> bool {
+        self.as_ref().flags & BLOCK_FLAG_FENCE != 0
+    }
+
+    /// Returns the start of the mapping for a mmapped block.
+    #[inline]
+    pub fn mapping(self) -> Unique<c_void> {
+        debug_assert!(self.is_mmapped());
+        unsafe { Unique::new_unchecked(self.cast::<c_void>().as_ptr().sub(self.offset())) }
+    }
+
+    /// Returns the total length of the mapping for a mmapped block, including the guard page.
+    #[inline]
+    pub fn mapping_size(&self) -> usize {
+        debug_assert!(self.is_mmapped());
+        let guard = if self.is_guarded() { *PAGE_SIZE } else { 0 };
+        self.offset() + self.block_size() + guard
+    }
+
+    /// Returns the distance from the start of the mapping to the block.
+    #[inline]
+    pub fn offset(&self) -> usize {
+        debug_assert!(self.is_mmapped());
+        self.as_ref().tag as usize
+    }
+
+    /// Returns the index of the arena owning this heap block.
+    #[inline]
+    pub fn arena(&self) -> usize {
+        debug_assert!(!self.is_mmapped());
+        self.as_ref().tag as usize
+    }
+
+    /// Assigns this heap block to the arena with the given index.
+    #[inline]
+    pub fn set_arena(&mut self, arena: usize) {
+        debug_assert!(!self.is_mmapped());
+        self.as_mut().tag = arena as u32;
+    }
+
+    /// Merges self with the physically following block,
+    /// which must not be part of any list anymore.
+    pub fn merge_next(&mut self) {
+        let next = self.next_block();
+        debug_assert!(!next.is_fence() && !next.is_free());
         dprintln!("[merge]: {} at {:p}", self.as_ref(), self.0);
         dprintln!("       & {} at {:p}", next.as_ref(), next);
-        // Update related links
-        self.as_mut().next = next.as_ref().next;
-        if let Some(mut n) = self.as_ref().next {
-            n.as_mut().prev = Some(self);
-        }
+
         // Update to final size
-        self.as_mut().size += BLOCK_META_SIZE + next.size();
+        self.as_mut().size += next.block_size();
+        stats::record_merge();
 
         // Overwrite block meta data for old block to detect double free
         unsafe {
             intrinsics::volatile_set_memory(next.cast::<c_void>().as_ptr(), 0, BLOCK_META_SIZE)
         };
-
         dprintln!("      -> {} at {:p}", self.as_ref(), self.0);
-        Some(self)
     }
 
     /// Shrinks the block in-place to have the exact memory size as specified (excluding metadata).
     /// Returns a newly created `BlockPtr` with the remaining size or `None` if split is not possible.
+    /// NOTE: The size is raised to `BLOCK_MIN_REGION_SIZE`, so the block can be freed later on.
     pub fn shrink(&mut self, size: usize) -> Option<BlockPtr> {
         dprintln!("[split]: {} at {:p}", self.as_ref(), self.0);
         debug_assert_eq!(
             size,
             util::pad_to_scalar(size).expect("unable to align").size()
         );
+        let size = cmp::max(size, BLOCK_MIN_REGION_SIZE);
         // Check if its possible to split the block with the requested size
         let rem_block_size = self.size().checked_sub(size + BLOCK_META_SIZE)?;
 
@@ -119,9 +410,11 @@ impl BlockPtr {
         // Update size for old block
         self.as_mut().size = size;
 
+        stats::record_split();
         // Create block with remaining size
         let new_block_ptr = unsafe { Unique::new_unchecked(self.mem_region().as_ptr().add(size)) };
-        let new_block = BlockPtr::new(new_block_ptr, rem_block_size);
+        let mut new_block = BlockPtr::new(new_block_ptr, rem_block_size);
+        new_block.set_arena(self.arena());
 
         dprintln!("      -> {} at {:p}", self.as_ref(), self.0);
         dprintln!("      -> {} at {:p}", new_block.as_ref(), new_block);
@@ -135,6 +428,39 @@ impl BlockPtr {
         );
         Some(new_block)
     }
+
+    /// Splits off the leading part of the block, so the memory region
+    /// of the remaining block is aligned to `align` (must be a power of two).
+    /// Returns the split off leading block (if any) and the aligned block,
+    /// or `None` if the block is too small to be aligned.
+    pub fn align_to(mut self, align: usize) -> Option<(Option<BlockPtr>, BlockPtr)> {
+        debug_assert!(align.is_power_of_two());
+        let region = self.mem_region().as_ptr() as usize;
+        let mut offset = util::align_up(region, align) - region;
+        if offset == 0 {
+            return Some((None, self));
+        }
+        // The leading slack must be large enough to form a block on its own.
+        if offset < BLOCK_META_SIZE + BLOCK_MIN_REGION_SIZE {
+            offset += align;
+        }
+
+        let lead_size = offset - BLOCK_META_SIZE;
+        let aligned_size = self.size().checked_sub(offset)?;
+        dprintln!("[align]: {} at {:p} to {}", self.as_ref(), self.0, align);
+
+        // Update size for leading block
+        self.as_mut().size = lead_size;
+        stats::record_split();
+
+        // Create aligned block right after the leading block
+        let mut aligned = BlockPtr::new(self.next_potential_block(), aligned_size);
+        aligned.set_arena(self.arena());
+        dprintln!("      -> {} at {:p}", self.as_ref(), self.0);
+        dprintln!("      -> {} at {:p}", aligned.as_ref(), aligned);
+        debug_assert_eq!(aligned.mem_region().as_ptr() as usize % align, 0);
+        Some((Some(self), aligned))
+    }
 }
 
 impl AsMut<Block> for BlockPtr {
@@ -175,6 +501,10 @@ pub struct Block {
     // Required metadata
     size: usize,
     magic: u16,
+    flags: u16,
+    /// Distance from the start of the mapping for mmapped blocks,
+    /// index of the owning arena for heap blocks.
+    tag: u32,
     // Memory region starts here. All following members will be
     // overwritten and are unusable if block has been allocated by a user.
     pub next: Option<BlockPtr>,
@@ -187,7 +517,10 @@ impl Block {
             size,
             next: None,
             prev: None,
-            magic: BLOCK_MAGIC_FREE,
+            // New blocks are in use until they are released.
+            magic: BLOCK_MAGIC_ALLOCATED,
+            flags: 0,
+            tag: 0,
         }
     }
 
@@ -198,10 +531,10 @@ impl Block {
     }
 
     /// Verifies block to detect memory corruption.
-    /// Returns `true` if block metadata is intact, `false` otherwise.
+    /// Returns `true` if block metadata holds a valid state, `false` otherwise.
     #[inline(always)]
     pub fn verify(&self) -> bool {
-        self.magic == BLOCK_MAGIC_FREE
+        BlockState::from_magic(self.magic).is_some()
     }
 }
 
@@ -216,8 +549,8 @@ impl fmt::Display for Block {
         )*/
         write!(
             f,
-            "Block(size={}, magic=0x{:X}, meta_size={})",
-            self.size, self.magic, BLOCK_META_SIZE,
+            "Block(size={}, magic=0x{:X}, flags=0x{:X}, meta_size={})",
+            self.size, self.magic, self.flags, BLOCK_META_SIZE,
         )
     }
 }
@@ -238,6 +571,10 @@ mod tests {
         assert!(block.as_ref().prev.is_none(), "prev is not None");
     }
 
+    fn block_size_of(size: usize) -> usize {
+        BLOCK_META_SIZE + size
+    }
+
     #[test]
     fn test_block_new() {
         let alloc_size = 64;
@@ -245,7 +582,9 @@ mod tests {
             Unique::new(libc::malloc(BLOCK_META_SIZE + alloc_size))
                 .expect("unable to allocate memory")
         };
-        assert_block(BlockPtr::new(ptr, alloc_size), alloc_size);
+        let block = BlockPtr::new(ptr, alloc_size);
+        assert_block(block, alloc_size);
+        assert!(!block.is_mmapped());
         unsafe { libc::free(ptr.as_ptr()) };
     }
 
@@ -304,6 +643,218 @@ mod tests {
         unsafe { libc::free(ptr.as_ptr()) };
     }
 
+    #[test]
+    fn test_block_align_to() {
+        let alloc_size = 4096;
+        let ptr = unsafe {
+            Unique::new(libc::malloc(BLOCK_META_SIZE + alloc_size))
+                .expect("unable to allocate memory")
+        };
+        let block = BlockPtr::new(ptr, alloc_size);
+        let (lead, aligned) = block.align_to(1024).expect("unable to align block");
+        assert_eq!(aligned.mem_region().as_ptr() as usize % 1024, 0);
+        assert!(aligned.as_ref().verify());
+        match lead {
+            Some(lead) => {
+                assert_eq!(lead, block);
+                assert!(lead.size() >= BLOCK_MIN_REGION_SIZE);
+                assert_eq!(
+                    lead.next_potential_block().as_ptr(),
+                    aligned.cast::<c_void>().as_ptr()
+                );
+                assert_eq!(
+                    lead.block_size() + aligned.block_size(),
+                    block_size_of(alloc_size)
+                );
+            }
+            None => assert_eq!(aligned, block),
+        }
+        unsafe { libc::free(ptr.as_ptr()) };
+    }
+
+    #[test]
+    fn test_block_split_keeps_arena() {
+        let alloc_size = 4096;
+        let ptr = unsafe {
+            Unique::new(libc::malloc(BLOCK_META_SIZE + alloc_size))
+                .expect("unable to allocate memory")
+        };
+        let mut block = BlockPtr::new(ptr, alloc_size);
+        assert_eq!(block.arena(), 0);
+        block.set_arena(3);
+        let rem = block.shrink(256).expect("split block failed");
+        assert_eq!(rem.arena(), 3);
+        let (_, aligned) = rem.align_to(1024).expect("unable to align block");
+        assert_eq!(aligned.arena(), 3);
+        unsafe { libc::free(ptr.as_ptr()) };
+    }
+
+    #[test]
+    fn test_block_align_to_too_small() {
+        let align = 4096;
+        let buf = unsafe { libc::malloc(align * 2) };
+        assert!(!buf.is_null());
+        // Place the block right behind an aligned address, so its region is misaligned.
+        let ptr = unsafe {
+            Unique::new_unchecked((util::align_up(buf as usize, align) + 16) as *mut c_void)
+        };
+        let block = BlockPtr::new(ptr, 64);
+        assert!(block.align_to(align).is_none());
+        unsafe { libc::free(buf) };
+    }
+
+    #[test]
+    fn test_block_new_mmapped() {
+        let alloc_size = 256;
+        let offset = 64;
+        let ptr = unsafe {
+            Unique::new(libc::malloc(offset + BLOCK_META_SIZE + alloc_size))
+                .expect("unable to allocate memory")
+        };
+        let block_ptr = unsafe { Unique::new_unchecked(ptr.as_ptr().add(offset)) };
+        let block = BlockPtr::new_mmapped(block_ptr, alloc_size, offset as u32);
+        assert_block(block, alloc_size);
+        assert!(block.is_mmapped() && !block.is_guarded());
+        assert_eq!(block.state(), Some(BlockState::Mmapped));
+        assert_eq!(block.offset(), offset);
+        assert_eq!(block.mapping().as_ptr(), ptr.as_ptr());
+        assert_eq!(block.mapping_size(), offset + BLOCK_META_SIZE + alloc_size);
+        unsafe { libc::free(ptr.as_ptr()) };
+    }
+
+    #[test]
+    fn test_block_new_guarded() {
+        let alloc_size = 256;
+        let offset = 64;
+        let len = offset + BLOCK_META_SIZE + alloc_size + *PAGE_SIZE;
+        let ptr = unsafe { Unique::new(libc::malloc(len)).expect("unable to allocate memory") };
+        let block_ptr = unsafe { Unique::new_unchecked(ptr.as_ptr().add(offset)) };
+        let block = BlockPtr::new_guarded(block_ptr, alloc_size, offset as u32);
+        assert_block(block, alloc_size);
+        assert!(block.is_mmapped() && block.is_guarded());
+        assert_eq!(block.state(), Some(BlockState::Mmapped));
+        assert_eq!(block.mapping().as_ptr(), ptr.as_ptr());
+        assert_eq!(block.mapping_size(), len);
+        unsafe { libc::free(ptr.as_ptr()) };
+    }
+
+    #[test]
+    fn test_block_new_fence() {
+        let ptr = unsafe {
+            Unique::new(libc::malloc(BLOCK_META_SIZE)).expect("unable to allocate memory")
+        };
+        let fence = BlockPtr::new_fence(ptr);
+        assert!(fence.as_ref().verify());
+        assert!(fence.is_fence());
+        assert_eq!(fence.state(), Some(BlockState::Fence));
+        assert!(!fence.is_free());
+        assert_eq!(fence.size(), 0);
+        unsafe { libc::free(ptr.as_ptr()) };
+    }
+
+    #[test]
+    fn test_block_set_free() {
+        let alloc_size = 64;
+        let ptr = unsafe {
+            Unique::new(libc::malloc(BLOCK_META_SIZE + alloc_size + BLOCK_META_SIZE))
+                .expect("unable to allocate memory")
+        };
+        let mut block = BlockPtr::new(ptr, alloc_size);
+        let fence = BlockPtr::new_fence(block.next_potential_block());
+        assert!(!block.is_free());
+        assert_eq!(block.state(), Some(BlockState::Allocated));
+        block.set_free(true);
+        assert!(block.is_free());
+        assert_eq!(block.state(), Some(BlockState::Free));
+        assert!(!block.is_mmapped() && !block.is_fence());
+        assert!(fence.is_prev_free());
+        assert_eq!(unsafe { *block.footer().as_ptr() }, alloc_size);
+        block.set_free(false);
+        assert!(!block.is_free());
+        assert_eq!(block.state(), Some(BlockState::Allocated));
+        assert!(!fence.is_prev_free());
+        unsafe { libc::free(ptr.as_ptr()) };
+    }
+
+    #[test]
+    fn test_block_set_sampled() {
+        let alloc_size = 64;
+        let ptr = unsafe {
+            Unique::new(libc::malloc(BLOCK_META_SIZE + alloc_size))
+                .expect("unable to allocate memory")
+        };
+        let mut block = BlockPtr::new(ptr, alloc_size);
+        assert!(!block.is_sampled());
+        block.set_sampled(true);
+        assert!(block.is_sampled());
+        assert!(!block.is_free() && !block.is_cached());
+        block.set_sampled(false);
+        assert!(!block.is_sampled());
+        unsafe { libc::free(ptr.as_ptr()) };
+    }
+
+    #[test]
+    fn test_block_prev_free_block() {
+        let block1_size = 1024;
+        let ptr = unsafe {
+            Unique::new(libc::malloc(
+                BLOCK_META_SIZE + block1_size + BLOCK_META_SIZE,
+            ))
+            .expect("unable to allocate memory")
+        };
+        let mut block1 = BlockPtr::new(ptr, block1_size);
+        let fence = BlockPtr::new_fence(block1.next_potential_block());
+        let mut block2 = block1.shrink(256).expect("split block failed");
+        assert_eq!(block2.prev_free_block(), None);
+
+        block1.set_free(true);
+        assert_eq!(block2.prev_free_block(), Some(block1));
+        block2.set_free(true);
+        assert_eq!(fence.prev_free_block(), Some(block2));
+        block1.set_free(false);
+        assert_eq!(block2.prev_free_block(), None);
+        unsafe { libc::free(ptr.as_ptr()) };
+    }
+
+    #[test]
+    fn test_block_shrink_min_region() {
+        let alloc_size = 256;
+        let ptr = unsafe {
+            Unique::new(libc::malloc(BLOCK_META_SIZE + alloc_size))
+                .expect("unable to allocate memory")
+        };
+        let mut block = BlockPtr::new(ptr, alloc_size);
+        let rem = block.shrink(16).expect("split block failed");
+        assert_eq!(block.size(), BLOCK_MIN_REGION_SIZE);
+        assert_eq!(
+            block.next_potential_block().as_ptr(),
+            rem.cast::<c_void>().as_ptr()
+        );
+        unsafe { libc::free(ptr.as_ptr()) };
+    }
+
+    #[test]
+    fn test_block_merge_next() {
+        let block1_size = 1024;
+        let ptr = unsafe {
+            Unique::new(libc::malloc(
+                BLOCK_META_SIZE + block1_size + BLOCK_META_SIZE,
+            ))
+            .expect("unable to allocate memory")
+        };
+        let mut block1 = BlockPtr::new(ptr, block1_size);
+        let fence = BlockPtr::new_fence(block1.next_potential_block());
+        let block2 = block1.shrink(256).expect("split block failed");
+        assert_eq!(block1.next_block(), block2);
+        assert_eq!(block2.next_block(), fence);
+
+        block1.merge_next();
+        assert_block(block1, block1_size);
+        assert_eq!(block1.next_block(), fence);
+        assert!(!block2.as_ref().verify());
+        unsafe { libc::free(ptr.as_ptr()) };
+    }
+
     #[test]
     fn test_block_verify_ok() {
         let alloc_size = 256;
@@ -325,7 +876,8 @@ mod tests {
         };
         let mut block = BlockPtr::new(ptr, alloc_size);
         block.as_mut().magic = 0x1234;
-        assert_eq!(block.as_ref().verify(), false);
+        assert!(!block.as_ref().verify());
+        assert_eq!(block.state(), None);
 
         unsafe { libc::free(ptr.as_ptr()) };
     }
@@ -0,0 +1,561 @@
+use core::ffi::{c_void, CStr};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{cmp, mem};
+
+use libc_print::libc_eprintln;
+
+use crate::alloc::arena::ARENA_LIMIT;
+use crate::leaks::{self, Mode};
+use crate::{profile, stats, trace};
+
+/// Name of the environment variable holding the configuration, see `Config::parse`.
+pub const CONF_ENV: &str = "COLLAM_CONF";
+/// NUL terminated name of the environment variable for `getenv`.
+const CONF_ENV_C: &[u8] = b"COLLAM_CONF\0";
+
+/// Default size from which on allocations are served by a dedicated mapping.
+pub const MMAP_THRESHOLD_DEFAULT: usize = 128 * 1024;
+/// Default size of the top free block from which on it is returned to the OS.
+pub const TRIM_THRESHOLD_DEFAULT: usize = 128 * 1024;
+
+/// Print a message if heap corruption is detected.
+pub const CHECK_ACTION_PRINT: usize = 1;
+/// Abort the process if heap corruption is detected.
+pub const CHECK_ACTION_ABORT: usize = 1 << 1;
+/// Print the call stack along with the message if heap corruption is detected.
+/// Only available through `Config` and `COLLAM_CONF`, since glibc assigns `M_CHECK_ACTION`
+/// bit 2 the opposite meaning.
+pub const CHECK_ACTION_BACKTRACE: usize = 1 << 8;
+
+/// Kind of heap corruption detected by the allocator.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The pointer has not been returned by the allocator or its header is corrupted.
+    InvalidPointer = 1,
+    /// The pointer has already been freed.
+    DoubleFree = 2,
+    /// The block header refers to an arena which does not exist.
+    InvalidArena = 3,
+    /// A neighbour of a block released to the free lists is corrupted.
+    CorruptedList = 4,
+    /// A quarantined block has been modified after it has been freed.
+    UseAfterFree = 5,
+}
+
+impl ErrorKind {
+    /// Returns a short description of the error.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::InvalidPointer => "invalid pointer",
+            ErrorKind::DoubleFree => "double free detected",
+            ErrorKind::InvalidArena => "invalid arena",
+            ErrorKind::CorruptedList => "corrupted free list",
+            ErrorKind::UseAfterFree => "write after free detected",
+        }
+    }
+}
+
+/// Callback invoked with the kind of detected heap corruption and the offending pointer,
+/// before the process is aborted if configured. It may allocate.
+pub type ErrorHandler = extern "C" fn(kind: ErrorKind, ptr: *mut c_void);
+
+/// Runtime configuration of a `Collam` instance, which can be changed at any time.
+pub struct Config {
+    mmap_threshold: AtomicUsize,
+    trim_threshold: AtomicUsize,
+    top_pad: AtomicUsize,
+    /// Maximum number of arenas, 0 if the default applies.
+    arena_max: AtomicUsize,
+    /// Fill byte for newly allocated memory, tagged with `JUNK_ENABLED`, 0 if disabled.
+    alloc_junk: AtomicUsize,
+    /// Fill byte for freed memory, tagged with `JUNK_ENABLED`, 0 if disabled.
+    free_junk: AtomicUsize,
+    check_action: AtomicUsize,
+    /// Size from which on allocations are followed by a guard page, `usize::MAX` if disabled.
+    guard_threshold: AtomicUsize,
+    /// Maximum sum of the sizes of quarantined blocks, 0 disables the quarantine.
+    quarantine: AtomicUsize,
+    /// Address of the `ErrorHandler`, 0 if none is registered.
+    error_handler: AtomicUsize,
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Config {
+            mmap_threshold: AtomicUsize::new(MMAP_THRESHOLD_DEFAULT),
+            trim_threshold: AtomicUsize::new(TRIM_THRESHOLD_DEFAULT),
+            top_pad: AtomicUsize::new(0),
+            arena_max: AtomicUsize::new(0),
+            alloc_junk: AtomicUsize::new(0),
+            free_junk: AtomicUsize::new(0),
+            check_action: AtomicUsize::new(CHECK_ACTION_PRINT),
+            guard_threshold: AtomicUsize::new(usize::MAX),
+            quarantine: AtomicUsize::new(0),
+            error_handler: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the size from which on allocations are served by a dedicated mapping.
+    pub fn set_mmap_threshold(&self, threshold: usize) {
+        self.mmap_threshold.store(threshold, Ordering::Relaxed);
+    }
+
+    /// Returns the size from which on allocations are served by a dedicated mapping.
+    pub fn mmap_threshold(&self) -> usize {
+        self.mmap_threshold.load(Ordering::Relaxed)
+    }
+
+    /// Sets the size the free block at the top of the heap must reach
+    /// to be returned to the OS. `usize::MAX` disables trimming.
+    pub fn set_trim_threshold(&self, threshold: usize) {
+        self.trim_threshold.store(threshold, Ordering::Relaxed);
+    }
+
+    /// Returns the size the free block at the top of the heap must reach
+    /// to be returned to the OS.
+    pub fn trim_threshold(&self) -> usize {
+        self.trim_threshold.load(Ordering::Relaxed)
+    }
+
+    /// Sets the amount of extra memory requested whenever the heap grows
+    /// and kept when the heap is trimmed.
+    pub fn set_top_pad(&self, pad: usize) {
+        self.top_pad.store(pad, Ordering::Relaxed);
+    }
+
+    /// Returns the amount of extra memory requested whenever the heap grows
+    /// and kept when the heap is trimmed.
+    pub fn top_pad(&self) -> usize {
+        self.top_pad.load(Ordering::Relaxed)
+    }
+
+    /// Sets the maximum number of arenas, which is capped to `ARENA_LIMIT`.
+    /// A maximum of 0 restores the default. Blocks of arenas above the new maximum
+    /// are still released to them, but no new blocks are reserved from them.
+    pub fn set_arena_max(&self, max: usize) {
+        self.arena_max
+            .store(cmp::min(max, ARENA_LIMIT), Ordering::Relaxed);
+    }
+
+    /// Returns the maximum number of arenas or 0 if the default applies.
+    pub fn arena_max(&self) -> usize {
+        self.arena_max.load(Ordering::Relaxed)
+    }
+
+    /// Sets the byte freed memory is filled with, allocated memory is filled
+    /// with its complement like with `M_PERTURB`. 0 disables filling.
+    pub fn set_perturb(&self, byte: u8) {
+        let enabled = byte != 0;
+        self.set_alloc_junk(enabled.then_some(!byte));
+        self.set_free_junk(enabled.then_some(byte));
+    }
+
+    /// Sets the byte newly allocated memory is filled with, `None` disables filling.
+    /// NOTE: Zeroed allocations are never filled, see `Collam::alloc_zeroed`.
+    pub fn set_alloc_junk(&self, byte: Option<u8>) {
+        self.alloc_junk
+            .store(junk_to_usize(byte), Ordering::Relaxed);
+    }
+
+    /// Returns the byte newly allocated memory is filled with, if enabled.
+    #[inline]
+    pub fn alloc_junk(&self) -> Option<u8> {
+        junk_from_usize(self.alloc_junk.load(Ordering::Relaxed))
+    }
+
+    /// Sets the byte freed memory is filled with, `None` disables filling.
+    pub fn set_free_junk(&self, byte: Option<u8>) {
+        self.free_junk.store(junk_to_usize(byte), Ordering::Relaxed);
+    }
+
+    /// Returns the byte freed memory is filled with, if enabled.
+    #[inline]
+    pub fn free_junk(&self) -> Option<u8> {
+        junk_from_usize(self.free_junk.load(Ordering::Relaxed))
+    }
+
+    /// Sets the action taken on detected heap corruption
+    /// as a combination of the `CHECK_ACTION_*` flags.
+    pub fn set_check_action(&self, action: usize) {
+        self.check_action.store(action, Ordering::Relaxed);
+    }
+
+    /// Returns the action taken on detected heap corruption.
+    pub fn check_action(&self) -> usize {
+        self.check_action.load(Ordering::Relaxed)
+    }
+
+    /// Sets the size from which on allocations are placed at the end of a dedicated mapping
+    /// followed by an inaccessible guard page, so overflows fault immediately.
+    /// 0 guards all allocations, `usize::MAX` disables guard pages.
+    pub fn set_guard_threshold(&self, threshold: usize) {
+        self.guard_threshold.store(threshold, Ordering::Relaxed);
+    }
+
+    /// Returns the size from which on allocations are followed by a guard page.
+    #[inline]
+    pub fn guard_threshold(&self) -> usize {
+        self.guard_threshold.load(Ordering::Relaxed)
+    }
+
+    /// Sets the maximum sum of the sizes of freed heap blocks, which are withheld
+    /// from reuse and checked for writes after free. 0 disables the quarantine.
+    pub fn set_quarantine(&self, bytes: usize) {
+        self.quarantine.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Returns the maximum sum of the sizes of quarantined blocks, 0 if disabled.
+    #[inline]
+    pub fn quarantine(&self) -> usize {
+        self.quarantine.load(Ordering::Relaxed)
+    }
+
+    /// Registers the callback invoked on detected heap corruption, `None` removes it.
+    pub fn set_error_handler(&self, handler: Option<ErrorHandler>) {
+        let addr = handler.map_or(0, |handler| handler as usize);
+        self.error_handler.store(addr, Ordering::Relaxed);
+    }
+
+    /// Returns the callback invoked on detected heap corruption, if any.
+    pub fn error_handler(&self) -> Option<ErrorHandler> {
+        match self.error_handler.load(Ordering::Relaxed) {
+            0 => None,
+            addr => Some(unsafe { mem::transmute::<usize, ErrorHandler>(addr) }),
+        }
+    }
+}
+
+impl Config {
+    /// Applies the configuration of the `COLLAM_CONF` environment variable, if set.
+    /// Returns the number of skipped entries.
+    pub fn parse_env(&self) -> usize {
+        let conf = unsafe { libc::getenv(CONF_ENV_C.as_ptr().cast()) };
+        if conf.is_null() {
+            return 0;
+        }
+        match unsafe { CStr::from_ptr(conf) }.to_str() {
+            Ok(conf) => self.parse(conf),
+            Err(_) => {
+                eprintln!("[libcollam.so]: {}: invalid UTF-8", CONF_ENV);
+                1
+            }
+        }
+    }
+
+    /// Applies the comma separated `key=value` entries of `conf`, for example
+    /// `mmap_threshold=256k,arena_max=2,junk=0xa5,stats_path=/tmp/stats.json`.
+    /// Sizes may be given in hex or with a `k`, `m` or `g` suffix.
+    /// Unknown keys and invalid values are reported and skipped.
+    /// Returns the number of skipped entries.
+    /// NOTE: Nothing is allocated, so this can be called on the first allocation.
+    pub fn parse(&self, conf: &str) -> usize {
+        let mut skipped = 0;
+        for entry in conf.split(',').filter(|entry| !entry.is_empty()) {
+            let (key, value) = match entry.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => (entry.trim(), ""),
+            };
+            if let Err(reason) = self.apply(key, value) {
+                eprintln!("[libcollam.so]: {}: {} '{}'", CONF_ENV, reason, entry);
+                skipped += 1;
+            }
+        }
+        skipped
+    }
+
+    /// Applies a single configuration entry. Returns the reason if it has been rejected.
+    fn apply(&self, key: &str, value: &str) -> Result<(), &'static str> {
+        match key {
+            "mmap_threshold" => self.set_mmap_threshold(parse_size(value)?),
+            "trim_threshold" => self.set_trim_threshold(parse_size(value)?),
+            "trim" if !parse_bool(value)? => self.set_trim_threshold(usize::MAX),
+            "trim" => {
+                // Only restore trimming if it has been disabled.
+                if self.trim_threshold() == usize::MAX {
+                    self.set_trim_threshold(TRIM_THRESHOLD_DEFAULT);
+                }
+            }
+            "top_pad" => self.set_top_pad(parse_size(value)?),
+            "arena_max" => self.set_arena_max(parse_size(value)?),
+            "junk" => match parse_size(value)? {
+                byte if byte <= u8::MAX as usize => self.set_perturb(byte as u8),
+                _ => return Err("junk byte out of range"),
+            },
+            "junk_alloc" => self.set_alloc_junk(parse_junk(value)?),
+            "junk_free" => self.set_free_junk(parse_junk(value)?),
+            "error_action" => self.set_check_action(parse_check_action(value)?),
+            "quarantine" => self.set_quarantine(parse_size(value)?),
+            "guard" => self.set_guard_threshold(match value {
+                "all" => 0,
+                "off" => usize::MAX,
+                value => parse_size(value)?,
+            }),
+            "stats_path" => {
+                if !stats::set_dump_path(value.as_bytes()) {
+                    return Err("invalid stats path");
+                }
+            }
+            "stats_signal" => {
+                if !stats::set_dump_signal(parse_signal(value)?) {
+                    return Err("unable to handle stats signal");
+                }
+            }
+            "prof_sample" => profile::set_interval(parse_size(value)?),
+            "prof_path" => {
+                if !profile::set_dump_path(value.as_bytes()) {
+                    return Err("invalid profile path");
+                }
+            }
+            "leak_check" => {
+                let mode = match value {
+                    "callers" => Mode::Callers,
+                    _ if parse_bool(value)? => Mode::On,
+                    _ => Mode::Off,
+                };
+                if !leaks::set_mode(mode) {
+                    return Err("unable to register leak report");
+                }
+            }
+            "trace_path" => {
+                if !trace::set_path(value.as_bytes()) {
+                    return Err("unable to open trace file");
+                }
+            }
+            _ => return Err("unknown key"),
+        }
+        Ok(())
+    }
+}
+
+/// Tags an enabled fill byte, so a fill byte of 0 can be told apart from disabled filling.
+const JUNK_ENABLED: usize = 1 << 8;
+
+#[inline]
+fn junk_to_usize(byte: Option<u8>) -> usize {
+    byte.map_or(0, |byte| JUNK_ENABLED | byte as usize)
+}
+
+#[inline]
+fn junk_from_usize(value: usize) -> Option<u8> {
+    match value {
+        0 => None,
+        value => Some(value as u8),
+    }
+}
+
+/// Parses a fill byte or `off`.
+fn parse_junk(value: &str) -> Result<Option<u8>, &'static str> {
+    match value {
+        "off" => Ok(None),
+        value => match parse_size(value)? {
+            byte if byte <= u8::MAX as usize => Ok(Some(byte as u8)),
+            _ => Err("junk byte out of range"),
+        },
+    }
+}
+
+/// Parses a check action given as `ignore` or as `+` separated combination
+/// of `print`, `backtrace` and `abort`. `backtrace` implies `print`.
+fn parse_check_action(value: &str) -> Result<usize, &'static str> {
+    if value == "ignore" {
+        return Ok(0);
+    }
+    value.split('+').try_fold(0, |action, flag| {
+        Ok(action
+            | match flag.trim() {
+                "print" => CHECK_ACTION_PRINT,
+                "backtrace" => CHECK_ACTION_PRINT | CHECK_ACTION_BACKTRACE,
+                "abort" => CHECK_ACTION_ABORT,
+                _ => return Err("invalid error action"),
+            })
+    })
+}
+
+/// Parses a decimal or `0x` prefixed hex number with an optional `k`, `m` or `g` suffix.
+fn parse_size(value: &str) -> Result<usize, &'static str> {
+    let (digits, shift) = match value.as_bytes().last() {
+        Some(b'k') | Some(b'K') => (&value[..value.len() - 1], 10),
+        Some(b'm') | Some(b'M') => (&value[..value.len() - 1], 20),
+        Some(b'g') | Some(b'G') => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    let number = match digits.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => digits.parse::<usize>(),
+    };
+    number
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or("invalid size")
+}
+
+/// Parses a boolean given as `1`/`0`, `true`/`false` or `on`/`off`.
+fn parse_bool(value: &str) -> Result<bool, &'static str> {
+    match value {
+        "1" | "true" | "on" => Ok(true),
+        "0" | "false" | "off" => Ok(false),
+        _ => Err("invalid boolean"),
+    }
+}
+
+/// Parses a signal given as number or name, with or without the `SIG` prefix.
+fn parse_signal(value: &str) -> Result<i32, &'static str> {
+    if let Ok(signal) = value.parse::<i32>() {
+        return Ok(signal);
+    }
+    match value.strip_prefix("SIG").unwrap_or(value) {
+        "HUP" => Ok(libc::SIGHUP),
+        "INT" => Ok(libc::SIGINT),
+        "QUIT" => Ok(libc::SIGQUIT),
+        "TERM" => Ok(libc::SIGTERM),
+        "USR1" => Ok(libc::SIGUSR1),
+        "USR2" => Ok(libc::SIGUSR2),
+        "PROF" => Ok(libc::SIGPROF),
+        _ => Err("invalid signal"),
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_defaults() {
+        let config = Config::new();
+        assert_eq!(config.mmap_threshold(), MMAP_THRESHOLD_DEFAULT);
+        assert_eq!(config.trim_threshold(), TRIM_THRESHOLD_DEFAULT);
+        assert_eq!(config.top_pad(), 0);
+        assert_eq!(config.arena_max(), 0);
+        assert_eq!(config.alloc_junk(), None);
+        assert_eq!(config.free_junk(), None);
+        assert_eq!(config.check_action(), CHECK_ACTION_PRINT);
+        assert_eq!(config.quarantine(), 0);
+        assert_eq!(config.guard_threshold(), usize::MAX);
+        assert!(config.error_handler().is_none());
+    }
+
+    #[test]
+    fn test_config_arena_max() {
+        let config = Config::new();
+        config.set_arena_max(3);
+        assert_eq!(config.arena_max(), 3);
+        config.set_arena_max(ARENA_LIMIT * 2);
+        assert_eq!(config.arena_max(), ARENA_LIMIT);
+    }
+
+    #[test]
+    fn test_config_parse() {
+        let config = Config::new();
+        let conf = "mmap_threshold=256k, trim_threshold=0x1000,top_pad=1m,arena_max=3,junk=0xa5,\
+                    error_action=print+abort,quarantine=64k";
+        assert_eq!(config.parse(conf), 0);
+        assert_eq!(config.mmap_threshold(), 256 * 1024);
+        assert_eq!(config.trim_threshold(), 4096);
+        assert_eq!(config.top_pad(), 1024 * 1024);
+        assert_eq!(config.arena_max(), 3);
+        assert_eq!(config.free_junk(), Some(0xa5));
+        assert_eq!(config.alloc_junk(), Some(0x5a));
+        assert_eq!(config.quarantine(), 64 * 1024);
+
+        assert_eq!(config.parse("guard=all"), 0);
+        assert_eq!(config.guard_threshold(), 0);
+        assert_eq!(config.parse("guard=4k"), 0);
+        assert_eq!(config.guard_threshold(), 4096);
+        assert_eq!(config.parse("guard=off"), 0);
+        assert_eq!(config.guard_threshold(), usize::MAX);
+        assert_eq!(
+            config.check_action(),
+            CHECK_ACTION_PRINT | CHECK_ACTION_ABORT
+        );
+
+        assert_eq!(config.parse("error_action=backtrace+abort"), 0);
+        assert_eq!(
+            config.check_action(),
+            CHECK_ACTION_PRINT | CHECK_ACTION_BACKTRACE | CHECK_ACTION_ABORT
+        );
+        assert_eq!(config.parse("error_action=ignore"), 0);
+        assert_eq!(config.check_action(), 0);
+        assert_eq!(config.parse("error_action=print+exit"), 1);
+        assert_eq!(config.check_action(), 0);
+
+        assert_eq!(config.parse("trim=off"), 0);
+        assert_eq!(config.trim_threshold(), usize::MAX);
+        assert_eq!(config.parse("trim=on"), 0);
+        assert_eq!(config.trim_threshold(), TRIM_THRESHOLD_DEFAULT);
+    }
+
+    #[test]
+    fn test_config_parse_invalid() {
+        let config = Config::new();
+        let conf = "unknown=1,mmap_threshold=12q,junk=256,trim,error_action=maybe,arena_max=2";
+        assert_eq!(config.parse(conf), 5);
+        // Valid entries are still applied.
+        assert_eq!(config.arena_max(), 2);
+        assert_eq!(config.mmap_threshold(), MMAP_THRESHOLD_DEFAULT);
+        assert_eq!(config.parse(""), 0);
+    }
+
+    #[test]
+    fn test_config_parse_leak_check() {
+        let config = Config::new();
+        assert_eq!(config.parse("leak_check=callers"), 0);
+        assert_eq!(leaks::mode(), Mode::Callers);
+        assert_eq!(config.parse("leak_check=maybe"), 1);
+        assert_eq!(config.parse("leak_check=off"), 0);
+        assert_eq!(leaks::mode(), Mode::Off);
+    }
+
+    #[test]
+    fn test_config_parse_profile() {
+        let config = Config::new();
+        assert_eq!(config.parse("prof_sample=64k,prof_path="), 0);
+        assert_eq!(profile::interval(), 64 * 1024);
+        assert_eq!(config.parse("prof_sample=0"), 0);
+        assert!(!profile::active());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("42"), Ok(42));
+        assert_eq!(parse_size("0x10"), Ok(16));
+        assert_eq!(parse_size("2K"), Ok(2048));
+        assert_eq!(parse_size("1g"), Ok(1 << 30));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("99999999999999999999g").is_err());
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("12"), Ok(12));
+        assert_eq!(parse_signal("SIGUSR2"), Ok(libc::SIGUSR2));
+        assert_eq!(parse_signal("USR1"), Ok(libc::SIGUSR1));
+        assert!(parse_signal("SIGFOO").is_err());
+    }
+
+    #[test]
+    fn test_config_perturb() {
+        let config = Config::new();
+        config.set_perturb(0xAB);
+        assert_eq!(config.free_junk(), Some(0xAB));
+        assert_eq!(config.alloc_junk(), Some(0x54));
+        config.set_perturb(0);
+        assert_eq!(config.free_junk(), None);
+        assert_eq!(config.alloc_junk(), None);
+
+        // Separate patterns, including a zero fill byte.
+        assert_eq!(config.parse("junk_alloc=0,junk_free=0x5a"), 0);
+        assert_eq!(config.alloc_junk(), Some(0));
+        assert_eq!(config.free_junk(), Some(0x5A));
+        assert_eq!(config.parse("junk_free=off,junk_alloc=0x100"), 1);
+        assert_eq!(config.free_junk(), None);
+        assert_eq!(config.alloc_junk(), Some(0));
+    }
+}
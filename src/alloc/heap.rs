@@ -0,0 +1,684 @@
+use core::cmp;
+use core::intrinsics::unlikely;
+use core::ptr::Unique;
+
+use libc_print::libc_eprintln;
+
+use crate::alloc::bins::Bins;
+use crate::alloc::block::{BlockPtr, BlockState, BLOCK_META_SIZE, BLOCK_MIN_REGION_SIZE};
+use crate::alloc::config::{Config, ErrorKind};
+use crate::alloc::info::{Fragmentation, Info};
+use crate::alloc::PAGE_SIZE;
+use crate::util;
+
+/// Minimum size of segments mapped by arenas not using the program break.
+const SEGMENT_SIZE_MIN: usize = 1024 * 1024;
+
+/// Heap memory of a single arena and the free blocks within it.
+/// Arena 0 obtains its memory with `sbrk`, all other arenas map segments with `mmap`.
+///
+/// Each contiguous segment of heap memory is terminated by a fence post,
+/// so the physical successor of any heap block can be looked up from its header.
+pub struct Heap {
+    bins: Bins,
+    /// Fence post of the most recently requested segment.
+    top: Option<BlockPtr>,
+    /// Index of the arena, which is stored in each block.
+    arena: usize,
+    /// Bytes of memory obtained from the OS.
+    system: usize,
+    /// Highest number of bytes obtained from the OS at any time.
+    system_max: usize,
+}
+
+impl Heap {
+    pub const fn new(arena: usize) -> Self {
+        Heap {
+            bins: Bins::new(),
+            top: None,
+            arena,
+            system: 0,
+            system_max: 0,
+        }
+    }
+
+    /// Reserves and returns suitable empty `BlockPtr`.
+    /// This can be either a reused empty block or a new one requested from kernel.
+    pub unsafe fn reserve(&mut self, size: usize, config: &Config) -> Option<BlockPtr> {
+        // Check for reusable blocks.
+        if let Some(block) = self.bins.pop(size) {
+            dprintln!("[pop]: {} at {:p}", block.as_ref(), block);
+            return Some(block);
+        }
+        // Request new block from kernel
+        self.request(size.checked_add(config.top_pad())?)
+    }
+
+    /// Releases the given `BlockPtr` to the free bins after merging it with
+    /// its free physical neighbours, if possible. Returns `Err` on a detected double free
+    /// or corrupted neighbour, in which case the free bins are left untouched.
+    /// NOTE: The memory is returned to the OS if it is adjacent to program break
+    /// and exceeds the trim threshold.
+    pub unsafe fn release(
+        &mut self,
+        mut block: BlockPtr,
+        config: &Config,
+    ) -> Result<(), ErrorKind> {
+        // Blocks held by a thread cache have been freed already as well.
+        if unlikely(block.is_free() || block.is_cached()) {
+            return Err(ErrorKind::DoubleFree);
+        }
+        debug_assert_eq!(block.arena(), self.arena);
+
+        // Neighbours are found through the boundary tags, so free blocks never touch.
+        // They are verified before any list is modified.
+        let next = block.next_block();
+        if unlikely(!next.as_ref().verify()) {
+            return Err(ErrorKind::CorruptedList);
+        }
+        let prev = if block.is_prev_free() {
+            Some(block.prev_block_unchecked())
+        } else {
+            None
+        };
+        if let Some(prev) = prev {
+            if unlikely(prev.state() != Some(BlockState::Free) || prev.next_block() != block) {
+                return Err(ErrorKind::CorruptedList);
+            }
+        }
+        if next.is_free() {
+            self.bins.remove(next);
+            block.merge_next();
+        }
+        if let Some(prev) = prev {
+            block = self.bins.remove(prev);
+            block.merge_next();
+        }
+
+        dprintln!("[insert]: {} at {:p}", block.as_ref(), block);
+        self.bins.insert(block);
+
+        // Large free blocks are given back to the OS, either by shrinking
+        // the heap or by dropping the pages.
+        if block.size() >= config.trim_threshold() {
+            let trimmed = Some(block.next_block()) == self.top && self.trim(config.top_pad());
+            if !trimmed {
+                purge(block);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the index of the arena.
+    #[inline]
+    #[allow(unused)]
+    pub fn arena(&self) -> usize {
+        self.arena
+    }
+
+    /// Returns a reference to the free bins.
+    #[inline]
+    #[allow(unused)]
+    pub fn bins(&self) -> &Bins {
+        &self.bins
+    }
+
+    /// Returns a summary of the heap memory.
+    pub fn info(&self) -> Info {
+        Info {
+            arena: self.system,
+            max_arena: self.system_max,
+            in_use: self.system - self.bins.bytes(),
+            free: self.bins.bytes(),
+            free_blocks: self.bins.count(),
+            releasable: self.releasable(),
+            ..Info::default()
+        }
+    }
+
+    /// Walks the free blocks and adds them to the fragmentation report.
+    pub fn fragmentation(&self, report: &mut Fragmentation) {
+        for block in self.bins.iter() {
+            report.add_free(block.size());
+        }
+        if self.arena == 0 {
+            report.trapped += self.bins.bytes() - self.releasable();
+        }
+    }
+
+    /// Returns the size of the free block at the top of the program break.
+    fn releasable(&self) -> usize {
+        if self.arena != 0 {
+            return 0;
+        }
+        self.top
+            .and_then(|top| top.prev_free_block())
+            .map_or(0, |block| block.size())
+    }
+
+    /// Prints some debugging information about the heap structure.
+    #[cfg(feature = "debug")]
+    pub fn debug(&self) {
+        self.bins.debug();
+        for block in self.bins.iter() {
+            debug_assert!(!block.is_prev_free());
+            debug_assert!(!block.next_block().is_free());
+            debug_assert!(block.next_block().is_prev_free());
+        }
+    }
+
+    /// Returns as much memory as possible to the OS: the top of the heap is trimmed,
+    /// keeping `pad` bytes, and the pages of all other free blocks are dropped.
+    /// Returns `true` if any memory has been returned.
+    pub unsafe fn trim_all(&mut self, pad: usize) -> bool {
+        let mut released = self.trim(pad);
+        for block in self.bins.iter() {
+            released |= purge(block);
+        }
+        released
+    }
+
+    /// Returns the memory of the free block at the top of the heap to the OS,
+    /// if it is the last block before program break. At least `pad` bytes are kept
+    /// in the block, if `pad` is not 0. Returns `true` if memory has been returned.
+    pub unsafe fn trim(&mut self, pad: usize) -> bool {
+        // Mapped segments may happen to end at the program break as well.
+        if self.arena != 0 {
+            return false;
+        }
+        let top = match self.top {
+            Some(top) => top,
+            None => return false,
+        };
+        let mut block = match top.prev_free_block() {
+            Some(block) => block,
+            None => return false,
+        };
+        match util::sbrk(0) {
+            Some(brk) if brk.as_ptr() == top.next_potential_block().as_ptr() => (),
+            _ => return false,
+        }
+
+        // The whole block is returned without padding, otherwise only whole pages
+        // are returned and the remaining block keeps at least `pad` bytes.
+        let offset = if pad == 0 {
+            block.block_size()
+        } else {
+            let keep = cmp::max(pad, BLOCK_MIN_REGION_SIZE);
+            match block.size().checked_sub(keep) {
+                Some(size) if size >= *PAGE_SIZE => size / *PAGE_SIZE * *PAGE_SIZE,
+                _ => return false,
+            }
+        };
+        dprintln!(
+            "[trim]: freeing {} bytes from process (break={:?})",
+            offset,
+            top.next_potential_block()
+        );
+
+        // The memory must not be touched anymore once it has been returned.
+        self.bins.remove(block);
+        let fence = if pad == 0 {
+            block
+        } else {
+            block
+                .shrink(block.size() - offset)
+                .expect("unable to split trimmed block")
+        };
+        if util::sbrk(-(offset as isize)).is_none() {
+            if fence != block {
+                block.merge_next();
+            }
+            self.bins.insert(block);
+            return false;
+        }
+        self.system -= offset;
+        // The block (or the split off part) becomes the new fence post of the shrunk segment.
+        self.top = Some(BlockPtr::new_fence(fence.cast()));
+        if fence != block {
+            self.bins.insert(block);
+        }
+        true
+    }
+
+    /// Requests memory for the specified size from kernel
+    /// and returns a `BlockPtr` to the newly created block or `None` if not possible.
+    /// The segment is extended if the new memory is contiguous to it.
+    unsafe fn request(&mut self, min_size: usize) -> Option<BlockPtr> {
+        let size = util::pad_to_align(BLOCK_META_SIZE * 2 + min_size, *PAGE_SIZE)
+            .ok()?
+            .size();
+        let (ptr, size) = if self.arena == 0 {
+            (util::sbrk(size as isize)?, size)
+        } else {
+            let size = cmp::max(size, SEGMENT_SIZE_MIN);
+            (util::mmap(size)?, size)
+        };
+        self.system += size;
+        self.system_max = cmp::max(self.system_max, self.system);
+
+        // The old fence post becomes the header of the new block, if contiguous.
+        // A free block in front of it is merged into the new block.
+        let start = match self.top {
+            Some(top) if top.next_potential_block().as_ptr() == ptr.as_ptr() => {
+                match top.prev_free_block() {
+                    Some(prev) => self.bins.remove(prev).cast(),
+                    None => top.cast(),
+                }
+            }
+            _ => ptr,
+        };
+        let end = ptr.as_ptr().add(size - BLOCK_META_SIZE);
+        self.top = Some(BlockPtr::new_fence(Unique::new_unchecked(end)));
+
+        let block_size = end as usize - start.as_ptr() as usize - BLOCK_META_SIZE;
+        let mut block = BlockPtr::new(start, block_size);
+        block.set_arena(self.arena);
+        Some(block)
+    }
+}
+
+/// Drops all pages within the memory region of the given free `BlockPtr`, which do
+/// not hold the free list links or the footer. Returns `true` if any page has been dropped.
+unsafe fn purge(block: BlockPtr) -> bool {
+    let region = block.mem_region().as_ptr() as usize;
+    let start = util::align_up(region + BLOCK_MIN_REGION_SIZE, *PAGE_SIZE);
+    let end = (region + block.size() - BLOCK_MIN_REGION_SIZE) / *PAGE_SIZE * *PAGE_SIZE;
+    if start >= end {
+        return false;
+    }
+    dprintln!("[purge]: dropping {} bytes of {:p}", end - start, block);
+    util::madvise_dontneed(Unique::new_unchecked(start as *mut _), end - start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a configuration, which always trims the heap.
+    fn config() -> Config {
+        let config = Config::new();
+        config.set_trim_threshold(0);
+        config
+    }
+
+    fn brk() -> *mut core::ffi::c_void {
+        unsafe { util::sbrk(0).expect("sbrk(0) failed").as_ptr() }
+    }
+
+    #[test]
+    fn test_request() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let block = heap.request(256).expect("unable to request block");
+            assert!(block.size() >= 256);
+            let top = heap.top.expect("no fence post");
+            assert!(top.is_fence());
+            assert_eq!(block.next_block(), top);
+            assert_eq!(top.next_potential_block().as_ptr(), brk());
+        }
+    }
+
+    #[test]
+    fn test_request_contiguous() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let block = heap.request(256).expect("unable to request block");
+            let fence = heap.top.expect("no fence post");
+            let block2 = heap.request(256).expect("unable to request block");
+            // The second block starts at the old fence post.
+            assert_eq!(block2, fence);
+            assert!(!block2.is_fence());
+            assert_eq!(block.next_block(), block2);
+            assert_eq!(block2.next_block(), heap.top.unwrap());
+        }
+    }
+
+    #[test]
+    fn test_request_mapped() {
+        unsafe {
+            let mut heap = Heap::new(2);
+            let brk_before = brk();
+            let block = heap.request(256).expect("unable to request block");
+            assert_eq!(brk(), brk_before);
+            assert_eq!(block.arena(), 2);
+            assert!(block.size() >= SEGMENT_SIZE_MIN - 2 * BLOCK_META_SIZE);
+            assert_eq!(block.next_block(), heap.top.unwrap());
+
+            // Mapped segments are never trimmed.
+            heap.release(block, &config()).expect("unable to release");
+            assert_eq!(heap.bins().count(), 1);
+
+            // Split blocks stay in the arena.
+            let mut block = heap
+                .reserve(64, &config())
+                .expect("unable to reserve block");
+            let rem = block.shrink(64).expect("unable to split block");
+            assert_eq!(rem.arena(), 2);
+            heap.release(rem, &config()).expect("unable to release");
+            heap.release(block, &config()).expect("unable to release");
+            assert_eq!(heap.bins().count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_info() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            assert_eq!(heap.info(), Info::default());
+            let mut block = heap.request(8192).expect("unable to request block");
+            let block2 = block.shrink(4096).expect("unable to split block");
+            let info = heap.info();
+            assert_eq!(
+                info.arena,
+                block.block_size() + block2.block_size() + BLOCK_META_SIZE
+            );
+            assert_eq!(info.in_use, info.arena);
+
+            let config = Config::new();
+            config.set_trim_threshold(usize::MAX);
+            heap.release(block2, &config).expect("unable to release");
+            let info = heap.info();
+            assert_eq!((info.free_blocks, info.free), (1, block2.size()));
+            assert_eq!(info.releasable, block2.size());
+            assert_eq!(info.in_use + info.free, info.arena);
+
+            let released = block2.block_size();
+            assert!(heap.trim(0));
+            let trimmed = heap.info();
+            assert_eq!(trimmed.arena, info.arena - released);
+            assert_eq!(trimmed.max_arena, info.arena);
+        }
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let mut block = heap.request(8192).expect("unable to request block");
+            let mut block2 = block.shrink(1024).expect("unable to split block");
+            let block3 = block2.shrink(2048).expect("unable to split block");
+            let config = Config::new();
+            config.set_trim_threshold(usize::MAX);
+            heap.release(block, &config).expect("unable to release");
+            heap.release(block3, &config).expect("unable to release");
+
+            // Only the top block can be trimmed.
+            let mut report = Fragmentation::new();
+            heap.fragmentation(&mut report);
+            assert_eq!(report.free_blocks, 2);
+            assert_eq!(report.free, 1024 + block3.size());
+            assert_eq!(report.largest_free, block3.size());
+            assert_eq!(report.trapped, 1024);
+            assert_eq!(report.histogram[10].count, 1);
+        }
+    }
+
+    #[test]
+    fn test_release_trims_top() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let block = heap.request(256).expect("unable to request block");
+            let brk_before = brk();
+            heap.release(block, &config()).expect("unable to release");
+            assert!(brk() < brk_before);
+            assert_eq!(heap.top, Some(block));
+            assert!(block.is_fence());
+            assert_eq!(heap.bins().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_release_trim_threshold() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let config = Config::new();
+            config.set_trim_threshold(8192);
+            let mut block = heap.request(8192).expect("unable to request block");
+            let block2 = block.shrink(4096).expect("unable to split block");
+            let brk_before = brk();
+
+            // The top block is smaller than the threshold.
+            heap.release(block2, &config).expect("unable to release");
+            assert_eq!(brk(), brk_before);
+            assert!(block2.is_free());
+
+            // The merged top block exceeds the threshold.
+            heap.release(block, &config).expect("unable to release");
+            assert!(brk() < brk_before);
+            assert_eq!(heap.top, Some(block));
+            assert_eq!(heap.bins().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_release_trim_pad() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let config = config();
+            config.set_top_pad(*PAGE_SIZE);
+            let block = heap
+                .request(*PAGE_SIZE * 4)
+                .expect("unable to request block");
+            let brk_before = brk();
+            heap.release(block, &config).expect("unable to release");
+
+            // Whole pages are returned and at least the padding is kept.
+            let released = brk_before as usize - brk() as usize;
+            assert!(released > 0 && released.is_multiple_of(*PAGE_SIZE));
+            assert!(block.is_free());
+            assert!(block.size() >= *PAGE_SIZE);
+            assert_eq!(block.next_block(), heap.top.unwrap());
+            assert_eq!(heap.top.unwrap().next_potential_block().as_ptr(), brk());
+            assert_eq!(heap.bins().count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_release_purges_pages() {
+        unsafe {
+            let mut heap = Heap::new(1);
+            let config = Config::new();
+            config.set_trim_threshold(64 * 1024);
+            let mut block = heap.request(256 * 1024).expect("unable to request block");
+            let block2 = block.shrink(128 * 1024).expect("unable to split block");
+            let region = block.mem_region().as_ptr().cast::<u8>();
+            region.write_bytes(0xAB, block.size());
+
+            heap.release(block, &config).expect("unable to release");
+            // The pages have been dropped, but the links are intact.
+            assert_eq!(*region.add(block.size() / 2), 0);
+            assert!(block.is_free());
+            assert_eq!(heap.bins().iter().next(), Some(block));
+            heap.release(block2, &config).expect("unable to release");
+        }
+    }
+
+    #[test]
+    fn test_trim_all() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let config = Config::new();
+            config.set_trim_threshold(usize::MAX);
+            let mut block = heap.request(256 * 1024).expect("unable to request block");
+            let mut block2 = block.shrink(128 * 1024).expect("unable to split block");
+            let block3 = block2.shrink(64).expect("unable to split block");
+            block.mem_region().as_ptr().write_bytes(0xAB, block.size());
+            heap.release(block, &config).expect("unable to release");
+            heap.release(block3, &config).expect("unable to release");
+            assert_ne!(*block.mem_region().as_ptr().cast::<u8>().add(64 * 1024), 0);
+            let brk_before = brk();
+
+            assert!(heap.trim_all(0));
+            assert!(brk() < brk_before);
+            assert_eq!(*block.mem_region().as_ptr().cast::<u8>().add(64 * 1024), 0);
+        }
+    }
+
+    #[test]
+    fn test_reserve_top_pad() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let config = config();
+            config.set_top_pad(64 * 1024);
+            let block = heap.reserve(64, &config).expect("unable to reserve block");
+            assert!(block.size() >= 64 * 1024 + 64);
+        }
+    }
+
+    #[test]
+    fn test_release_foreign_brk() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let block = heap.request(256).expect("unable to request block");
+            // Memory requested by someone else prevents trimming.
+            util::sbrk(4096).expect("sbrk failed");
+            heap.release(block, &config()).expect("unable to release");
+            assert!(block.is_free());
+            assert_eq!(heap.bins().count(), 1);
+            util::sbrk(-4096).expect("sbrk failed");
+
+            // A new segment is created if memory is not contiguous.
+            util::sbrk(4096).expect("sbrk failed");
+            let fence = heap.top.unwrap();
+            let block2 = heap.request(8192).expect("unable to request block");
+            assert_ne!(block2, fence);
+            assert!(fence.is_fence());
+        }
+    }
+
+    #[test]
+    fn test_release_merge_without_trim() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let mut block = heap.request(1024).expect("unable to request block");
+            let mut block2 = block.shrink(64).expect("unable to split block");
+            let block3 = block2.shrink(64).expect("unable to split block");
+            // block3 stays in use, block2 is released first, then block
+            heap.release(block2, &config()).expect("unable to release");
+            assert_eq!(heap.bins().count(), 1);
+            heap.release(block, &config()).expect("unable to release");
+            assert_eq!(heap.bins().count(), 1);
+            assert_eq!(block.size(), 64 + BLOCK_META_SIZE + 64);
+            assert_eq!(block.next_block(), block3);
+            heap.release(block3, &config()).expect("unable to release");
+        }
+    }
+
+    #[test]
+    fn test_release_merge_prev() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let mut block = heap.request(1024).expect("unable to request block");
+            let mut block2 = block.shrink(64).expect("unable to split block");
+            let block3 = block2.shrink(64).expect("unable to split block");
+            // block3 stays in use, block is released first, then block2
+            heap.release(block, &config()).expect("unable to release");
+            assert!(block2.is_prev_free());
+            heap.release(block2, &config()).expect("unable to release");
+            assert_eq!(heap.bins().count(), 1);
+            assert_eq!(block.size(), 64 + BLOCK_META_SIZE + 64);
+            assert!(block.is_free());
+            assert_eq!(block3.prev_free_block(), Some(block));
+            heap.release(block3, &config()).expect("unable to release");
+        }
+    }
+
+    #[test]
+    fn test_release_merge_both() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let mut block = heap.request(1024).expect("unable to request block");
+            let mut block2 = block.shrink(64).expect("unable to split block");
+            let mut block3 = block2.shrink(64).expect("unable to split block");
+            let block4 = block3.shrink(64).expect("unable to split block");
+            heap.release(block, &config()).expect("unable to release");
+            heap.release(block3, &config()).expect("unable to release");
+            assert_eq!(heap.bins().count(), 2);
+            heap.release(block2, &config()).expect("unable to release");
+            assert_eq!(heap.bins().count(), 1);
+            assert_eq!(block.size(), 3 * 64 + 2 * BLOCK_META_SIZE);
+            assert_eq!(block.next_block(), block4);
+            heap.release(block4, &config()).expect("unable to release");
+        }
+    }
+
+    #[test]
+    fn test_request_merges_free_top() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let block = heap.request(256).expect("unable to request block");
+            // Memory requested by someone else prevents trimming.
+            util::sbrk(4096).expect("sbrk failed");
+            heap.release(block, &config()).expect("unable to release");
+            util::sbrk(-4096).expect("sbrk failed");
+
+            let size = block.size();
+            let block2 = heap.request(8192).expect("unable to request block");
+            assert_eq!(block2, block);
+            assert!(!block2.is_free());
+            assert!(block2.size() > size + 8192);
+            assert_eq!(heap.bins().count(), 0);
+            heap.release(block2, &config()).expect("unable to release");
+        }
+    }
+
+    #[test]
+    fn test_release_double_free() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let mut block = heap.request(1024).expect("unable to request block");
+            let block2 = block.shrink(64).expect("unable to split block");
+            heap.release(block, &config()).expect("unable to release");
+            assert_eq!(heap.release(block, &config()), Err(ErrorKind::DoubleFree));
+            heap.release(block2, &config()).expect("unable to release");
+        }
+    }
+
+    #[test]
+    fn test_release_corrupted_neighbour() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let mut block = heap.request(1024).expect("unable to request block");
+            let mut block2 = block.shrink(64).expect("unable to split block");
+            let block3 = block2.shrink(64).expect("unable to split block");
+
+            // Releasing a block next to a corrupted header leaves the bins untouched.
+            let header = core::ptr::read(block3.as_ptr());
+            block3.cast::<u8>().as_ptr().write_bytes(0, BLOCK_META_SIZE);
+            assert_eq!(
+                heap.release(block2, &config()),
+                Err(ErrorKind::CorruptedList)
+            );
+            assert!(!block2.is_free());
+            core::ptr::write(block3.as_ptr(), header);
+
+            // Same for a corrupted footer of the free predecessor.
+            heap.release(block, &config()).expect("unable to release");
+            let footer = block2.cast::<usize>().as_ptr().sub(1);
+            *footer += 16;
+            assert_eq!(
+                heap.release(block2, &config()),
+                Err(ErrorKind::CorruptedList)
+            );
+            *footer -= 16;
+
+            heap.release(block2, &config()).expect("unable to release");
+            heap.release(block3, &config()).expect("unable to release");
+        }
+    }
+
+    #[test]
+    fn test_reserve_reuses_block() {
+        unsafe {
+            let mut heap = Heap::new(0);
+            let mut block = heap.request(1024).expect("unable to request block");
+            let block2 = block.shrink(128).expect("unable to split block");
+            heap.release(block, &config()).expect("unable to release");
+            assert_eq!(heap.reserve(128, &config()), Some(block));
+            assert!(!block.is_free());
+            heap.release(block, &config()).expect("unable to release");
+            heap.release(block2, &config()).expect("unable to release");
+        }
+    }
+}
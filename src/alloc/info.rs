@@ -0,0 +1,376 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{cmp, fmt};
+
+use crate::alloc::arena::ARENA_LIMIT;
+use crate::alloc::block::BLOCK_META_SIZE;
+use crate::alloc::Collam;
+use crate::stats;
+
+/// Number of buckets of the free block histogram, one per power of two.
+pub const HISTOGRAM_BUCKETS: usize = usize::BITS as usize;
+
+/// Summary of the memory managed by a `Collam` instance or one of its arenas,
+/// modeled after the fields of `mallinfo(3)`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Info {
+    /// Bytes of heap memory obtained from the OS, excluding dedicated mappings.
+    pub arena: usize,
+    /// Highest number of bytes of heap memory obtained from the OS at any time,
+    /// summed up over all arenas.
+    pub max_arena: usize,
+    /// Bytes of heap memory in use, including block headers and cached blocks.
+    pub in_use: usize,
+    /// Bytes held by free heap blocks.
+    pub free: usize,
+    /// Number of free heap blocks.
+    pub free_blocks: usize,
+    /// Number of blocks with a dedicated mapping.
+    pub mmapped_blocks: usize,
+    /// Bytes of all dedicated mappings.
+    pub mmapped: usize,
+    /// Highest number of blocks with a dedicated mapping at any time.
+    pub max_mmapped_blocks: usize,
+    /// Highest number of bytes of all dedicated mappings at any time.
+    pub max_mmapped: usize,
+    /// Bytes of the free block at the top of the heap, which can be trimmed.
+    pub releasable: usize,
+}
+
+/// Sizes of the free blocks within a single bin or histogram bucket.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SizeClass {
+    /// Size of the smallest block.
+    pub from: usize,
+    /// Size of the largest block.
+    pub to: usize,
+    /// Sum of the sizes of all blocks.
+    pub total: usize,
+    /// Number of blocks.
+    pub count: usize,
+}
+
+/// Report on the fragmentation of the free heap memory of a `Collam` instance
+/// and the memory wasted within the blocks in use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fragmentation {
+    /// Free blocks by size, bucket `i` holds the blocks of `2^i` to `2^(i+1) - 1` bytes.
+    pub histogram: [SizeClass; HISTOGRAM_BUCKETS],
+    /// Bytes held by free heap blocks.
+    pub free: usize,
+    /// Number of free heap blocks.
+    pub free_blocks: usize,
+    /// Size of the largest free heap block.
+    pub largest_free: usize,
+    /// Free bytes below the program break, which cannot be trimmed
+    /// as they are not part of the top block.
+    pub trapped: usize,
+    /// Bytes lost by rounding up requested sizes, summed up over all allocations of the process.
+    pub padding: usize,
+    /// Bytes of the headers of all blocks in use by the process.
+    pub headers: usize,
+}
+
+impl Fragmentation {
+    /// Returns an empty report including the waste of the blocks in use.
+    pub(crate) fn new() -> Self {
+        let stats = stats::snapshot();
+        Fragmentation {
+            histogram: [SizeClass::default(); HISTOGRAM_BUCKETS],
+            free: 0,
+            free_blocks: 0,
+            largest_free: 0,
+            trapped: 0,
+            padding: stats.granted_bytes.saturating_sub(stats.requested_bytes),
+            headers: stats.allocations.saturating_sub(stats.frees) * BLOCK_META_SIZE,
+        }
+    }
+
+    /// Adds a free block of the given size to the report.
+    pub(crate) fn add_free(&mut self, size: usize) {
+        let class = &mut self.histogram[bucket_index(size)];
+        if class.count == 0 || size < class.from {
+            class.from = size;
+        }
+        class.to = cmp::max(class.to, size);
+        class.total += size;
+        class.count += 1;
+        self.free += size;
+        self.free_blocks += 1;
+        self.largest_free = cmp::max(self.largest_free, size);
+    }
+
+    /// Returns the external fragmentation as the share of free memory,
+    /// which is not part of the largest free block. 0 if no memory is free.
+    pub fn ratio(&self) -> f64 {
+        if self.free == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free as f64 / self.free as f64
+    }
+
+    /// Writes the report as text, omitting empty histogram buckets.
+    pub fn write(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(out, "free bytes       = {:>10}", self.free)?;
+        writeln!(out, "free blocks      = {:>10}", self.free_blocks)?;
+        writeln!(out, "largest free     = {:>10}", self.largest_free)?;
+        writeln!(out, "fragmentation    = {:>10.4}", self.ratio())?;
+        writeln!(out, "trapped bytes    = {:>10}", self.trapped)?;
+        writeln!(out, "padding bytes    = {:>10}", self.padding)?;
+        writeln!(out, "header bytes     = {:>10}", self.headers)?;
+        for (idx, class) in self.histogram.iter().enumerate() {
+            if class.count != 0 {
+                writeln!(
+                    out,
+                    "[{}, {}): {} blocks, {} bytes",
+                    1usize << idx,
+                    (1usize << idx).saturating_mul(2),
+                    class.count,
+                    class.total
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the histogram bucket of free blocks of the given size.
+#[inline]
+fn bucket_index(size: usize) -> usize {
+    (usize::BITS - 1 - (size | 1).leading_zeros()) as usize
+}
+
+impl Info {
+    /// Adds the heap memory of an arena to the summary.
+    pub(crate) fn add_arena(&mut self, arena: &Info) {
+        self.arena += arena.arena;
+        self.max_arena += arena.max_arena;
+        self.in_use += arena.in_use;
+        self.free += arena.free;
+        self.free_blocks += arena.free_blocks;
+        self.releasable += arena.releasable;
+    }
+}
+
+/// Counters of the blocks with a dedicated mapping.
+pub(crate) struct Mappings {
+    blocks: AtomicUsize,
+    bytes: AtomicUsize,
+    max_blocks: AtomicUsize,
+    max_bytes: AtomicUsize,
+}
+
+impl Mappings {
+    pub const fn new() -> Self {
+        Mappings {
+            blocks: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            max_blocks: AtomicUsize::new(0),
+            max_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Records a new mapping of `len` bytes.
+    pub fn map(&self, len: usize) {
+        let blocks = self.blocks.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_blocks.fetch_max(blocks, Ordering::Relaxed);
+        self.grow(len);
+    }
+
+    /// Records the removal of a mapping of `len` bytes.
+    pub fn unmap(&self, len: usize) {
+        self.blocks.fetch_sub(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    /// Records the resize of a mapping from `old_len` to `new_len` bytes.
+    pub fn remap(&self, old_len: usize, new_len: usize) {
+        if new_len > old_len {
+            self.grow(new_len - old_len);
+        } else {
+            self.bytes.fetch_sub(old_len - new_len, Ordering::Relaxed);
+        }
+    }
+
+    fn grow(&self, len: usize) {
+        let bytes = self.bytes.fetch_add(len, Ordering::Relaxed) + len;
+        self.max_bytes.fetch_max(bytes, Ordering::Relaxed);
+    }
+
+    /// Sets the current and highest mapping counts of the summary.
+    pub fn fill(&self, info: &mut Info) {
+        info.mmapped_blocks = self.blocks.load(Ordering::Relaxed);
+        info.mmapped = self.bytes.load(Ordering::Relaxed);
+        info.max_mmapped_blocks = self.max_blocks.load(Ordering::Relaxed);
+        info.max_mmapped = self.max_bytes.load(Ordering::Relaxed);
+    }
+}
+
+/// Writes the memory summary of `collam` in the XML format of `malloc_info(3)`,
+/// with a size histogram of the free blocks of each arena.
+pub fn write_xml(collam: &Collam, out: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(out, "<malloc version=\"1\">")?;
+    let mut total = Info::default();
+    // Arenas beyond a lowered arena maximum may still hold memory.
+    for idx in 0..ARENA_LIMIT {
+        let (info, classes) = match collam.arenas.get(idx) {
+            Some(heap) => {
+                // Nothing may be written while locked, the output may allocate.
+                let heap = heap.lock();
+                (heap.info(), heap.bins().size_classes())
+            }
+            None => continue,
+        };
+        if info.arena == 0 {
+            continue;
+        }
+        total.add_arena(&info);
+
+        writeln!(out, "<heap nr=\"{}\">", idx)?;
+        writeln!(out, "<sizes>")?;
+        for class in classes.iter().filter(|class| class.count != 0) {
+            writeln!(
+                out,
+                "  <size from=\"{}\" to=\"{}\" total=\"{}\" count=\"{}\"/>",
+                class.from, class.to, class.total, class.count
+            )?;
+        }
+        writeln!(out, "</sizes>")?;
+        write_totals(out, &info, false)?;
+        writeln!(out, "</heap>")?;
+    }
+    collam.mappings.fill(&mut total);
+    write_totals(out, &total, true)?;
+    writeln!(out, "</malloc>")
+}
+
+/// Writes the free block, mapping and system memory totals of `info`.
+/// The mapping totals are only written if `mmap` is set.
+fn write_totals(out: &mut dyn fmt::Write, info: &Info, mmap: bool) -> fmt::Result {
+    // Small blocks are cached per thread instead of being kept in fastbins.
+    writeln!(out, "<total type=\"fast\" count=\"0\" size=\"0\"/>")?;
+    writeln!(
+        out,
+        "<total type=\"rest\" count=\"{}\" size=\"{}\"/>",
+        info.free_blocks, info.free
+    )?;
+    if mmap {
+        writeln!(
+            out,
+            "<total type=\"mmap\" count=\"{}\" size=\"{}\"/>",
+            info.mmapped_blocks, info.mmapped
+        )?;
+    }
+    writeln!(out, "<system type=\"current\" size=\"{}\"/>", info.arena)?;
+    writeln!(out, "<system type=\"max\" size=\"{}\"/>", info.max_arena)?;
+    writeln!(out, "<aspace type=\"total\" size=\"{}\"/>", info.arena)?;
+    writeln!(out, "<aspace type=\"mprotect\" size=\"{}\"/>", info.arena)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mappings() {
+        let mappings = Mappings::new();
+        mappings.map(8192);
+        mappings.map(4096);
+        mappings.remap(4096, 16384);
+        mappings.unmap(8192);
+        mappings.remap(16384, 4096);
+
+        let mut info = Info::default();
+        mappings.fill(&mut info);
+        assert_eq!(info.mmapped_blocks, 1);
+        assert_eq!(info.mmapped, 4096);
+        assert_eq!(info.max_mmapped_blocks, 2);
+        assert_eq!(info.max_mmapped, 8192 + 16384);
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let mut report = Fragmentation::new();
+        assert_eq!(report.ratio(), 0.0);
+        report.add_free(32);
+        report.add_free(48);
+        report.add_free(4096);
+        assert_eq!(bucket_index(48), 5);
+        let class = report.histogram[5];
+        assert_eq!(
+            (class.from, class.to, class.total, class.count),
+            (32, 48, 80, 2)
+        );
+        assert_eq!(report.histogram[12].count, 1);
+        assert_eq!((report.free, report.free_blocks), (4176, 3));
+        assert_eq!(report.largest_free, 4096);
+        assert!((report.ratio() - 80.0 / 4176.0).abs() < 1e-9);
+
+        let mut text = std::string::String::new();
+        report.write(&mut text).expect("unable to write report");
+        assert!(text.contains("[32, 64): 2 blocks, 80 bytes\n"));
+        assert!(text.contains("largest free     =       4096\n"));
+    }
+
+    #[test]
+    fn test_write_xml() {
+        use core::alloc::{GlobalAlloc, Layout};
+        use std::string::String;
+
+        let collam = Collam::new();
+        collam.config().set_arena_max(1);
+        collam.config().set_trim_threshold(usize::MAX);
+        unsafe {
+            let layout = Layout::from_size_align_unchecked(4096, 16);
+            let ptr = collam.alloc(layout);
+            let ptr2 = collam.alloc(layout);
+            collam.dealloc(ptr, layout);
+
+            let mut xml = String::new();
+            collam.write_info(&mut xml).expect("unable to write info");
+            let info = collam.info();
+            assert!(xml.starts_with("<malloc version=\"1\">\n<heap nr=\"0\">\n<sizes>\n"));
+            assert!(xml.ends_with("</malloc>\n"));
+            assert_eq!(xml.matches("<heap ").count(), 1);
+            assert!(xml.contains("  <size from=\"4096\" to=\"4096\" total=\"4096\" count=\"1\"/>"));
+            let rest = format!(
+                "<total type=\"rest\" count=\"{}\" size=\"{}\"/>",
+                info.free_blocks, info.free
+            );
+            assert_eq!(xml.matches(rest.as_str()).count(), 2);
+            assert!(xml.contains("<total type=\"mmap\" count=\"0\" size=\"0\"/>"));
+            let system = format!("<system type=\"current\" size=\"{}\"/>", info.arena);
+            assert_eq!(xml.matches(system.as_str()).count(), 2);
+            collam.dealloc(ptr2, layout);
+        }
+    }
+
+    #[test]
+    fn test_write_xml_arena_max() {
+        use core::alloc::{GlobalAlloc, Layout};
+        use std::string::String;
+
+        let collam = Collam::new();
+        collam.config().set_arena_max(2);
+        unsafe {
+            let layout = Layout::from_size_align_unchecked(4096, 16);
+            let ptr = collam.alloc(layout);
+            // The next thread is bound to the other arena.
+            let other = std::thread::scope(|s| {
+                s.spawn(|| collam.alloc(layout) as usize)
+                    .join()
+                    .expect("thread panicked")
+            }) as *mut u8;
+
+            // Arenas beyond a lowered maximum are still reported.
+            collam.config().set_arena_max(1);
+            let mut xml = String::new();
+            collam.write_info(&mut xml).expect("unable to write info");
+            let info = collam.info();
+            assert_eq!(xml.matches("<heap ").count(), 2);
+            let system = format!("<system type=\"current\" size=\"{}\"/>", info.arena);
+            assert_eq!(xml.matches(system.as_str()).count(), 1);
+            collam.dealloc(other, layout);
+            collam.dealloc(ptr, layout);
+        }
+    }
+}
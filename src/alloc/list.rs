@@ -1,6 +1,8 @@
 use libc_print::libc_eprintln;
 
-use crate::alloc::block::{BlockPtr, BLOCK_SPLIT_MIN_SIZE};
+use crate::alloc::block::BlockPtr;
+use crate::alloc::info::SizeClass;
+use core::cmp;
 use core::intrinsics::unlikely;
 
 #[repr(C)]
@@ -17,9 +19,8 @@ impl IntrusiveList {
         }
     }
 
-    /// Inserts a `BlockPtr` to the existing list and
-    /// returns `Err` on detected double-free.
-    pub unsafe fn insert(&mut self, mut to_insert: BlockPtr) -> Result<(), ()> {
+    /// Appends a `BlockPtr` to the end of the list.
+    pub unsafe fn insert(&mut self, mut to_insert: BlockPtr) {
         // Reset pointer locations since they were part as user allocatable data
         to_insert.as_mut().unlink();
 
@@ -28,58 +29,62 @@ impl IntrusiveList {
             debug_assert!(self.tail.is_none());
             self.head = Some(to_insert);
             self.tail = Some(to_insert);
-            return Ok(());
+            return;
         }
 
         debug_assert!(self.head.is_some());
         debug_assert!(self.tail.is_some());
 
-        match self.find_higher_block(to_insert)? {
-            Some(block) => IntrusiveList::insert_before(block, to_insert),
-            None => IntrusiveList::insert_after(self.tail.unwrap(), to_insert),
-        }
-        let inserted = IntrusiveList::maybe_merge_adjacent(to_insert);
-        self.update_ends(inserted);
-        Ok(())
+        IntrusiveList::insert_after(self.tail.unwrap(), to_insert);
+        self.tail = Some(to_insert);
     }
 
-    /// Removes and returns the first suitable `BlockPtr`.
+    /// Removes and returns the first `BlockPtr` with at least the given size.
     #[inline]
     pub fn pop(&mut self, size: usize) -> Option<BlockPtr> {
         for block in self.iter() {
-            unsafe {
-                if size == block.size() {
-                    dprintln!(
-                        "[libcollam.so]: found perfect {} at {:p} for size {}",
-                        block.as_ref(),
-                        block,
-                        size
-                    );
-                    return Some(self.remove(block));
-                }
-                if size + BLOCK_SPLIT_MIN_SIZE <= block.size() {
-                    dprintln!(
-                        "[libcollam.so]: found suitable {} at {:p} for size {}",
-                        block.as_ref(),
-                        block,
-                        size
-                    );
-                    return Some(self.remove(block));
-                }
+            if size <= block.size() {
+                dprintln!(
+                    "[libcollam.so]: found suitable {} at {:p} for size {}",
+                    block.as_ref(),
+                    block,
+                    size
+                );
+                return Some(unsafe { self.remove(block) });
             }
         }
         None
     }
 
-    /// Prints some debugging information about the heap structure.
+    /// Returns `true` if the list contains no blocks.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    /// Walks the list and returns the size range, total size and number of its blocks.
+    pub fn size_class(&self) -> SizeClass {
+        self.iter().fold(SizeClass::default(), |mut class, block| {
+            let size = block.size();
+            if class.count == 0 || size < class.from {
+                class.from = size;
+            }
+            class.to = cmp::max(class.to, size);
+            class.total += size;
+            class.count += 1;
+            class
+        })
+    }
+
+    /// Prints some debugging information about the list structure.
     #[cfg(feature = "debug")]
     pub fn debug(&self) {
-        dprintln!("[debug]: === list debug start ===");
         for (i, block) in self.iter().enumerate() {
             dprintln!("[debug]: pos: {}\t{} at\t{:p}", i, block.as_ref(), block);
-            if !block.as_ref().verify() {
+            if block.state() != Some(crate::alloc::block::BlockState::Free) {
                 panic!("Unable to verify: {} at\t{:p}", block.as_ref(), block);
             }
+            debug_assert!(block.is_free());
 
             match block.as_ref().prev {
                 Some(prev) => {
@@ -98,31 +103,6 @@ impl IntrusiveList {
                 }
                 None => debug_assert_eq!(self.tail.unwrap().as_ptr(), block.as_ptr()),
             }
-
-            if let Some(next) = block.as_ref().next {
-                debug_assert!(
-                    block.as_ptr() < next.as_ptr(),
-                    "{:p} is not smaller than {:p}",
-                    block,
-                    next
-                );
-            }
-        }
-        dprintln!("[debug]: === list debug end ===");
-    }
-
-    /// Adds a `BlockPtr` to the list before the given anchor.
-    unsafe fn insert_before(mut anchor: BlockPtr, mut to_insert: BlockPtr) {
-        // Update links in new block
-        to_insert.as_mut().prev = anchor.as_ref().prev;
-        to_insert.as_mut().next = Some(anchor);
-
-        // Update link for element after new block
-        anchor.as_mut().prev = Some(to_insert);
-
-        // Update link for element before new block
-        if let Some(mut prev) = to_insert.as_ref().prev {
-            prev.as_mut().next = Some(to_insert);
         }
     }
 
@@ -141,52 +121,8 @@ impl IntrusiveList {
         }
     }
 
-    /// Checks if head or tail should be updated with the given `BlockPtr`.
-    #[inline]
-    unsafe fn update_ends(&mut self, block: BlockPtr) {
-        // Update head if necessary
-        if block.as_ref().prev.is_none() {
-            self.head = Some(block);
-        }
-
-        // Update tail if necessary
-        if block.as_ref().next.is_none() {
-            self.tail = Some(block);
-        }
-    }
-
-    /// Takes a `BlockPtr` and tries to merge adjacent blocks if possible.
-    /// Always returns a `BlockPtr`.
-    #[inline]
-    unsafe fn maybe_merge_adjacent(block: BlockPtr) -> BlockPtr {
-        let block = match block.as_ref().prev {
-            Some(prev) => prev.maybe_merge_next().unwrap_or(block),
-            None => block,
-        };
-        block.maybe_merge_next().unwrap_or(block)
-    }
-
-    /// Returns first `BlockPtr` that has a higher memory address than the given `BlockPtr`
-    /// or `None` if no block exists at a higher memory address.
-    /// Returns `Err` if given `BlockPtr` is already in list.
-    /// TODO: implement with better algorithm
-    #[inline]
-    fn find_higher_block(&self, to_insert: BlockPtr) -> Result<Option<BlockPtr>, ()> {
-        for block in self.iter() {
-            if block.as_ptr() > to_insert.as_ptr() {
-                return Ok(Some(block));
-            }
-            if block == to_insert {
-                // block is already in list.
-                // One reason for this is double free()
-                return Err(());
-            }
-        }
-        Ok(None)
-    }
-
     /// Removes the given `BlockPtr` from list and returns it.
-    unsafe fn remove(&mut self, mut elem: BlockPtr) -> BlockPtr {
+    pub unsafe fn remove(&mut self, mut elem: BlockPtr) -> BlockPtr {
         // Update head
         if let Some(head) = self.head {
             if elem == head {
@@ -225,9 +161,8 @@ pub struct Iter {
 impl Iterator for Iter {
     type Item = BlockPtr;
     fn next(&mut self) -> Option<Self::Item> {
-        self.next.map(|node| {
+        self.next.inspect(|node| {
             self.next = node.as_ref().next;
-            node
         })
     }
 }
@@ -236,150 +171,135 @@ impl Iterator for Iter {
 mod tests {
     use super::*;
     use crate::alloc::block::BLOCK_META_SIZE;
-    use crate::alloc::request_block;
+    use core::ffi::c_void;
+    use core::ptr::Unique;
 
-    #[test]
-    fn test_insert_after_no_merge() {
-        let mut list = IntrusiveList::new();
-        assert_eq!(list.head, None);
-        assert_eq!(list.tail, None);
+    const BUFFER_SIZE: usize = 3 * (BLOCK_META_SIZE + 64) + BLOCK_META_SIZE;
+
+    #[repr(align(16))]
+    struct Buffer([u8; BUFFER_SIZE]);
 
-        let mut block = unsafe { request_block(256).expect("unable to request block") };
-        // Block2 imitates a used block. So it will not be added to list
+    /// Returns three adjacent blocks with a size of 64 bytes followed by a fence post
+    /// within the given buffer.
+    fn blocks(buf: &mut Buffer) -> (BlockPtr, BlockPtr, BlockPtr) {
+        let ptr = unsafe { Unique::new_unchecked(buf.0.as_mut_ptr().cast::<c_void>()) };
+        let mut block = BlockPtr::new(ptr, BUFFER_SIZE - 2 * BLOCK_META_SIZE);
+        BlockPtr::new_fence(block.next_potential_block());
         let mut block2 = block.shrink(64).expect("unable to split block");
         let block3 = block2.shrink(64).expect("unable to split block");
+        (block, block2, block3)
+    }
 
-        // Insert block1
-        unsafe { list.insert(block).expect("unable to insert") };
-        assert_eq!(list.head, Some(block));
-        assert_eq!(list.tail, Some(block));
-        assert_eq!(block.as_ref().next, None);
-        assert_eq!(block.as_ref().prev, None);
-
-        // Insert block3
-        unsafe { list.insert(block3).expect("unable to insert") };
-        assert_eq!(list.head, Some(block));
-        assert_eq!(list.tail, Some(block3));
-        assert_eq!(block.as_ref().next, Some(block3));
-        assert_eq!(block.as_ref().prev, None);
-        assert_eq!(block3.as_ref().next, None);
-        assert_eq!(block3.as_ref().prev, Some(block));
+    #[test]
+    fn test_size_class() {
+        let mut buf = Buffer([0; BUFFER_SIZE]);
+        let (block, block2, block3) = blocks(&mut buf);
+        let mut list = IntrusiveList::new();
+        assert_eq!(list.size_class(), SizeClass::default());
+        unsafe {
+            list.insert(block);
+            list.insert(block3);
+            list.insert(block2);
+        }
+        let class = list.size_class();
+        assert_eq!((class.from, class.to), (64, 64));
+        assert_eq!((class.total, class.count), (192, 3));
     }
 
     #[test]
-    fn test_insert_before_no_merge() {
+    fn test_insert() {
+        let mut buf = Buffer([0; BUFFER_SIZE]);
+        let (block, _, block3) = blocks(&mut buf);
         let mut list = IntrusiveList::new();
         assert_eq!(list.head, None);
         assert_eq!(list.tail, None);
-
-        let mut block = unsafe { request_block(256).expect("unable to request block") };
-        // Block2 imitates a used block. So it will not be added to list
-        let mut block2 = block.shrink(64).expect("unable to split block");
-        let block3 = block2.shrink(64).expect("unable to split block");
+        assert!(list.is_empty());
 
         // Insert block3
-        unsafe { list.insert(block3).expect("unable to insert") };
+        unsafe { list.insert(block3) };
         assert_eq!(list.head, Some(block3));
         assert_eq!(list.tail, Some(block3));
         assert_eq!(block3.as_ref().next, None);
         assert_eq!(block3.as_ref().prev, None);
 
-        // Insert block1
-        unsafe { list.insert(block).expect("unable to insert") };
-        assert_eq!(list.head, Some(block));
-        assert_eq!(list.tail, Some(block3));
-        assert_eq!(block.as_ref().next, Some(block3));
-        assert_eq!(block.as_ref().prev, None);
-        assert_eq!(block3.as_ref().next, None);
-        assert_eq!(block3.as_ref().prev, Some(block));
+        // Insert block1, blocks are appended regardless of their address
+        unsafe { list.insert(block) };
+        assert_eq!(list.head, Some(block3));
+        assert_eq!(list.tail, Some(block));
+        assert_eq!(block3.as_ref().next, Some(block));
+        assert_eq!(block3.as_ref().prev, None);
+        assert_eq!(block.as_ref().next, None);
+        assert_eq!(block.as_ref().prev, Some(block3));
+        assert!(!list.is_empty());
     }
 
     #[test]
-    fn test_insert_merge() {
+    fn test_remove() {
+        let mut buf = Buffer([0; BUFFER_SIZE]);
+        let (block, block2, block3) = blocks(&mut buf);
         let mut list = IntrusiveList::new();
-        assert_eq!(list.head, None);
+        unsafe {
+            list.insert(block);
+            list.insert(block2);
+            list.insert(block3);
+
+            // Remove from the middle
+            assert_eq!(list.remove(block2), block2);
+            assert_eq!(block.as_ref().next, Some(block3));
+            assert_eq!(block3.as_ref().prev, Some(block));
+
+            // Remove head and tail
+            list.remove(block);
+            assert_eq!(list.head, Some(block3));
+            list.remove(block3);
+        }
+        assert!(list.is_empty());
         assert_eq!(list.tail, None);
-
-        let mut block = unsafe { request_block(256).expect("unable to request block") };
-        let mut block2 = block.shrink(64).expect("unable to split block");
-        let block3 = block2.shrink(64).expect("unable to split block");
-
-        // Insert block1
-        unsafe { list.insert(block).expect("unable to insert") };
-        assert_eq!(list.head, Some(block));
-        assert_eq!(list.tail, Some(block));
-        assert_eq!(block.as_ref().next, None);
-        assert_eq!(block.as_ref().prev, None);
-        assert_eq!(block.size(), 64);
-
-        // Insert block2
-        unsafe { list.insert(block2).expect("unable to insert") };
-        assert_eq!(list.head, Some(block));
-        assert_eq!(list.tail, Some(block));
-        assert_eq!(block.as_ref().next, None);
-        assert_eq!(block.as_ref().prev, None);
-        assert_eq!(block.size(), 64 + BLOCK_META_SIZE + 64);
-
-        // Insert block3
-        unsafe { list.insert(block3).expect("unable to insert") };
-        assert_eq!(list.head, Some(block));
-        assert_eq!(list.tail, Some(block));
-        assert_eq!(block.as_ref().next, None);
-        assert_eq!(block.as_ref().prev, None);
-        assert!(block.size() > 64 + BLOCK_META_SIZE + 64 + BLOCK_META_SIZE);
     }
 
     #[test]
     fn test_pop_exact_size() {
+        let mut buf = Buffer([0; BUFFER_SIZE]);
+        let (block, _, block3) = blocks(&mut buf);
         let mut list = IntrusiveList::new();
-        let mut block = unsafe { request_block(512).expect("unable to request block") };
-        // Block2 imitates a used block. So it will not be added to list
-        let mut block2 = block.shrink(64).expect("unable to split block");
-        let block3 = block2.shrink(64).expect("unable to split block");
-
-        // Insert block1
-        unsafe { list.insert(block).expect("unable to insert") };
-        // Insert block3
-        unsafe { list.insert(block3).expect("unable to insert") };
+        unsafe {
+            list.insert(block);
+            list.insert(block3);
+        }
 
         let result = list.pop(64).expect("got no block");
         assert_eq!(result, block);
         assert_eq!(result.as_ref().next, None);
         assert_eq!(result.as_ref().prev, None);
         assert_eq!(result.size(), 64);
+        assert_eq!(list.head, Some(block3));
     }
 
     #[test]
     fn test_pop_smaller_size() {
+        let mut buf = Buffer([0; BUFFER_SIZE]);
+        let (block, _, block3) = blocks(&mut buf);
         let mut list = IntrusiveList::new();
-        let mut block = unsafe { request_block(512).expect("unable to request block") };
-        // Block2 imitates a used block. So it will not be added to list
-        let mut block2 = block.shrink(64).expect("unable to split block");
-        let block3 = block2.shrink(64).expect("unable to split block");
-
-        // Insert block1
-        unsafe { list.insert(block).expect("unable to insert") };
-        // Insert block3
-        unsafe { list.insert(block3).expect("unable to insert") };
+        unsafe {
+            list.insert(block);
+            list.insert(block3);
+        }
 
         let result = list.pop(16).expect("got no block");
         assert_eq!(result, block);
-        assert_eq!(result.as_ref().next, None);
-        assert_eq!(result.as_ref().prev, None);
         assert_eq!(result.size(), 64);
+        assert!(list.pop(128).is_none());
     }
 
     #[test]
     fn test_iter() {
+        let mut buf = Buffer([0; BUFFER_SIZE]);
+        let (block, _, block3) = blocks(&mut buf);
         let mut list = IntrusiveList::new();
-        let mut block = unsafe { request_block(256).expect("unable to request block") };
-        let mut block2 = block.shrink(64).expect("unable to split block");
-        let block3 = block2.shrink(64).expect("unable to split block");
-
-        // Insert block1
-        unsafe { list.insert(block).expect("unable to insert") };
-        // Insert block3
-        unsafe { list.insert(block3).expect("unable to insert") };
+        unsafe {
+            list.insert(block);
+            list.insert(block3);
+        }
 
         let mut iter = list.iter();
         assert_eq!(iter.next().unwrap(), block);
@@ -390,16 +310,15 @@ mod tests {
     #[cfg(feature = "debug")]
     #[test]
     fn test_debug() {
+        let mut buf = Buffer([0; BUFFER_SIZE]);
+        let (mut block, _, mut block3) = blocks(&mut buf);
         let mut list = IntrusiveList::new();
-        let mut block = unsafe { request_block(256).expect("unable to request block") };
-        // Block2 imitates a used block. So it will not be added to list
-        let mut block2 = block.shrink(64).expect("unable to split block");
-        let block3 = block2.shrink(64).expect("unable to split block");
-
-        // Insert block1
-        unsafe { list.insert(block).expect("unable to insert") };
-        // Insert block3
-        unsafe { list.insert(block3).expect("unable to insert") };
+        block.set_free(true);
+        block3.set_free(true);
+        unsafe {
+            list.insert(block);
+            list.insert(block3);
+        }
         list.debug()
     }
 }
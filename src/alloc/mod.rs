@@ -1,99 +1,277 @@
 use core::alloc::{GlobalAlloc, Layout};
+use core::fmt::Write;
 use core::intrinsics::unlikely;
-use core::{cmp, ffi::c_void, intrinsics, ptr::null_mut, ptr::Unique};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::{cmp, ffi::c_void, fmt, intrinsics, mem, ptr::null_mut, ptr::Unique};
 
 use libc_print::libc_eprintln;
 
-use crate::alloc::block::{BlockPtr, BLOCK_META_SIZE};
-use crate::alloc::list::IntrusiveList;
-#[cfg(feature = "stats")]
-use crate::stats;
-use crate::util;
+use crate::alloc::arena::Arenas;
+pub use crate::alloc::arena::ARENA_LIMIT;
+pub use crate::alloc::bins::NUM_BINS;
+use crate::alloc::block::{Block, BlockPtr, BlockState, BLOCK_META_SIZE, BLOCK_MIN_REGION_SIZE};
+use crate::alloc::config::{
+    Config, CHECK_ACTION_ABORT, CHECK_ACTION_BACKTRACE, CHECK_ACTION_PRINT,
+};
+pub use crate::alloc::config::{ErrorHandler, ErrorKind};
+pub use crate::alloc::config::{MMAP_THRESHOLD_DEFAULT, TRIM_THRESHOLD_DEFAULT};
+use crate::alloc::info::Mappings;
+pub use crate::alloc::info::{Fragmentation, Info, SizeClass, HISTOGRAM_BUCKETS};
+use crate::alloc::quarantine::Quarantine;
+use crate::alloc::tcache::TCache;
+use crate::util::FdWriter;
+use crate::{leaks, profile, stats, util};
 
+mod arena;
+mod bins;
 pub mod block;
+pub mod config;
+mod heap;
+mod info;
 mod list;
+mod quarantine;
+mod tcache;
 
 lazy_static! {
-    static ref PAGE_SIZE: usize = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    pub(crate) static ref PAGE_SIZE: usize = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
 }
 
+/// Source of the ids identifying `Collam` instances in thread caches.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// NOTE: Small blocks freed by a thread are cached by it and flushed back
+/// on thread exit, so an instance must not be moved once it has been used.
 pub struct Collam {
-    heap: spin::Mutex<IntrusiveList>,
+    arenas: Arenas,
+    config: Config,
+    mappings: Mappings,
+    quarantine: spin::Mutex<Quarantine>,
+    /// Lazily assigned id, 0 if not assigned yet.
+    id: AtomicUsize,
+    /// Set once the configuration of the environment has been read.
+    env_read: AtomicBool,
 }
 
 impl Collam {
-    #[allow(unused)]
     pub const fn new() -> Self {
         Collam {
-            heap: spin::Mutex::new(IntrusiveList::new()),
+            arenas: Arenas::new(),
+            config: Config::new(),
+            mappings: Mappings::new(),
+            quarantine: spin::Mutex::new(Quarantine::new()),
+            id: AtomicUsize::new(0),
+            env_read: AtomicBool::new(false),
         }
     }
 
-    /// Reserves and returns suitable empty `BlockPtr`.
-    /// This can be either a reused empty block or a new one requested from kernel.
-    unsafe fn reserve_block(&self, size: usize) -> Option<BlockPtr> {
-        // Locking this whole function is critical since break will be increased!
-        let mut heap = self.heap.lock();
+    /// Returns the id uniquely identifying this instance.
+    fn id(&self) -> usize {
+        let id = self.id.load(Ordering::Relaxed);
+        if id != 0 {
+            return id;
+        }
+        let new_id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        match self
+            .id
+            .compare_exchange(0, new_id, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => new_id,
+            Err(id) => id,
+        }
+    }
 
-        // Check for reusable blocks.
-        if let Some(block) = (*heap).pop(size) {
-            dprintln!("[pop]: {} at {:p}", block.as_ref(), block);
-            return Some(block);
+    /// Returns the runtime configuration.
+    #[inline]
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Applies the configuration of the `COLLAM_CONF` environment variable,
+    /// unless it has been read already. Called on the first allocation.
+    /// NOTE: Allocations of other threads may be served before it has been applied.
+    pub fn read_env(&self) {
+        // Allocations made while applying the configuration must not wait for it.
+        if self.env_read.swap(true, Ordering::Relaxed) {
+            return;
         }
-        // Request new block from kernel
-        request_block(size)
+        self.config.parse_env();
     }
 
-    /// Releases the given `BlockPtr` back to the allocator.
-    /// NOTE: The memory is returned to the OS if it is adjacent to program break.
-    unsafe fn release_block(&self, block: BlockPtr) {
-        // Lock heap for the whole function
-        let mut heap = self.heap.lock();
+    /// Returns the maximum number of arenas in use.
+    pub fn arena_max(&self) -> usize {
+        match self.config.arena_max() {
+            0 => arena::max_default(),
+            max => max,
+        }
+    }
 
-        #[cfg(feature = "debug")]
-        {
-            (*heap).debug();
+    /// Returns a summary of the heap memory of the arena with the given index,
+    /// or `None` if it does not exist.
+    pub fn arena_info(&self, idx: usize) -> Option<Info> {
+        Some(self.arenas.get(idx)?.lock().info())
+    }
+
+    /// Returns a summary of the memory of all arenas and dedicated mappings.
+    pub fn info(&self) -> Info {
+        let mut info = Info::default();
+        for idx in 0..arena::ARENA_LIMIT {
+            if let Some(arena) = self.arena_info(idx) {
+                info.add_arena(&arena);
+            }
         }
-        #[cfg(feature = "stats")]
-        {
-            stats::update_ends((*heap).head, (*heap).tail);
-            stats::print();
+        self.mappings.fill(&mut info);
+        info
+    }
+
+    /// Returns the sizes of the free blocks in each bin of the arena
+    /// with the given index, or `None` if it does not exist.
+    pub fn arena_sizes(&self, idx: usize) -> Option<[SizeClass; NUM_BINS]> {
+        Some(self.arenas.get(idx)?.lock().bins().size_classes())
+    }
+
+    /// Returns a report on the fragmentation of the free memory of all arenas.
+    pub fn fragmentation(&self) -> Fragmentation {
+        let mut report = Fragmentation::new();
+        for idx in 0..arena::ARENA_LIMIT {
+            if let Some(heap) = self.arenas.get(idx) {
+                heap.lock().fragmentation(&mut report);
+            }
         }
+        report
+    }
 
-        let ptr = block.next_potential_block();
-        if let Some(brk) = util::sbrk(0) {
-            if ptr.as_ptr() == brk.as_ptr() {
-                let offset = block.block_size() as isize;
-                dprintln!(
-                    "[insert]: freeing {} bytes from process (break={:?})",
-                    offset,
-                    ptr
-                );
-                util::sbrk(-offset);
-                return;
+    /// Writes a summary of the memory of all arenas in the XML format of `malloc_info(3)`.
+    pub fn write_info(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        info::write_xml(self, out)
+    }
+
+    /// Returns free memory of all arenas to the OS, keeping `pad` bytes at the top
+    /// of the program break. Blocks cached by the current thread are released first.
+    /// Returns `true` if any memory has been returned.
+    pub fn trim(&self, pad: usize) -> bool {
+        unsafe {
+            let cache = TCache::get();
+            if cache.owns(self) {
+                cache.flush();
+            }
+        }
+        let mut released = false;
+        for idx in 0..arena::ARENA_LIMIT {
+            if let Some(heap) = self.arenas.get(idx) {
+                released |= unsafe { heap.lock().trim_all(pad) };
             }
         }
+        released
+    }
 
-        dprintln!("[insert]: {} at {:p}", block.as_ref(), block);
-        if unlikely((*heap).insert(block).is_err()) {
-            eprintln!("double free detected for ptr {:?}", block.mem_region());
+    /// Reports heap corruption of `kind` detected by `op` for the user pointer `ptr`
+    /// of `size` bytes, if known, according to the configured check action
+    /// and invokes the error handler, if any.
+    pub(crate) fn report_error(
+        &self,
+        op: &str,
+        kind: ErrorKind,
+        ptr: *mut c_void,
+        size: Option<usize>,
+    ) {
+        let action = self.config.check_action();
+        if action & CHECK_ACTION_PRINT != 0 {
+            match size {
+                Some(size) => eprintln!(
+                    "{}(): {} for ptr {:p} ({} bytes)",
+                    op,
+                    kind.as_str(),
+                    ptr,
+                    size
+                ),
+                None => eprintln!("{}(): {} for ptr {:p}", op, kind.as_str(), ptr),
+            }
+        }
+        if action & CHECK_ACTION_BACKTRACE != 0 {
+            write_backtrace();
+        }
+        if let Some(handler) = self.config.error_handler() {
+            handler(kind, ptr);
+        }
+        if action & CHECK_ACTION_ABORT != 0 {
+            unsafe { libc::abort() };
         }
     }
-}
 
-unsafe impl GlobalAlloc for Collam {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    /// Checks the state of a block passed by the user to `op`.
+    /// Returns `true` if the block is in use, otherwise the invalid or double free
+    /// is recorded and reported and the block must not be touched any further.
+    pub(crate) unsafe fn check_in_use(&self, block: BlockPtr, op: &str) -> bool {
+        // The memory region is derived directly, since the header may not be valid.
+        let ptr = block
+            .cast::<u8>()
+            .as_ptr()
+            .add(BLOCK_META_SIZE)
+            .cast::<c_void>();
+        let (kind, size) = match block.state() {
+            Some(BlockState::Allocated) | Some(BlockState::Mmapped) => return true,
+            Some(BlockState::Free) | Some(BlockState::Quarantined) => {
+                stats::record_double_free();
+                (ErrorKind::DoubleFree, Some(block.size()))
+            }
+            Some(BlockState::Fence) | None => {
+                stats::record_failed_verify();
+                (ErrorKind::InvalidPointer, None)
+            }
+        };
+        self.report_error(op, kind, ptr, size);
+        false
+    }
+
+    /// Allocates a block for `layout`, whose memory is zeroed if `zeroed` is set
+    /// and filled with the alloc junk byte otherwise, if enabled.
+    unsafe fn allocate(&self, layout: Layout, zeroed: bool) -> *mut u8 {
         if layout.size() == 0 {
             return null_mut();
         }
+        if unlikely(!self.env_read.load(Ordering::Relaxed)) {
+            self.read_env();
+        }
 
+        let align = layout.align();
+        let requested = layout.size();
         let layout = match util::pad_to_scalar(layout.size()) {
             Ok(l) => l,
             Err(_) => return null_mut(),
         };
 
-        dprintln!("[libcollam.so]: alloc(size={})", layout.size());
-        let mut block = match self.reserve_block(layout.size()) {
+        dprintln!(
+            "[libcollam.so]: alloc(size={}, align={})",
+            layout.size(),
+            align
+        );
+        if unlikely(layout.size() >= self.config.guard_threshold()) {
+            if let Some(block) = self.map_guarded_block(requested, align) {
+                stats::record_alloc(requested, block.size());
+                // Fresh mappings are zeroed already, so their pages are left untouched.
+                if !zeroed {
+                    self.junk_alloc(block, requested);
+                }
+                return self.sampled(block, requested);
+            }
+        }
+        if layout.size() >= self.config.mmap_threshold() {
+            if let Some(block) = self.map_block(layout.size(), align) {
+                stats::record_alloc(requested, block.size());
+                // Fresh mappings are zeroed already, so their pages are left untouched.
+                if !zeroed {
+                    self.junk_alloc(block, layout.size());
+                }
+                return self.sampled(block, requested);
+            }
+        }
+
+        let block = if align > mem::align_of::<libc::max_align_t>() {
+            self.reserve_aligned_block(layout.size(), align)
+        } else {
+            self.reserve_block(layout.size())
+        };
+        let mut block = match block {
             Some(b) => b,
             None => {
                 dprintln!("[libcollam.so]: failed for size: {}\n", layout.size());
@@ -116,7 +294,298 @@ unsafe impl GlobalAlloc for Collam {
             layout.size(),
             block.as_ref()
         );
-        block.mem_region().cast::<u8>().as_ptr()
+        stats::record_alloc(requested, block.size());
+        if zeroed {
+            intrinsics::volatile_set_memory(block.mem_region().as_ptr(), 0, requested);
+        } else {
+            self.junk_alloc(block, layout.size());
+        }
+        self.sampled(block, requested)
+    }
+
+    /// Reserves and returns suitable empty `BlockPtr`.
+    /// This can be either a reused empty block or a new one requested from kernel.
+    unsafe fn reserve_block(&self, size: usize) -> Option<BlockPtr> {
+        // Serve from the thread cache without locking, if possible.
+        if let Some(block) = TCache::get().pop(self, size) {
+            return Some(block);
+        }
+        // Locking this whole function is critical since break will be increased!
+        let arena = self.arenas.current(self.id(), self.arena_max());
+        self.arenas.get(arena)?.lock().reserve(size, &self.config)
+    }
+
+    /// Reserves and returns a `BlockPtr` with a memory region aligned to `align`.
+    /// The leading slack required for alignment is split off and released.
+    unsafe fn reserve_aligned_block(&self, size: usize, align: usize) -> Option<BlockPtr> {
+        // Worst case padding required to split off a valid leading block.
+        let padding = align + BLOCK_META_SIZE + BLOCK_MIN_REGION_SIZE;
+        let block = self.reserve_block(size.checked_add(padding)?)?;
+        let (lead, block) = block
+            .align_to(align)
+            .expect("reserved block is too small for alignment");
+        if let Some(lead) = lead {
+            self.release_block(lead);
+        }
+        Some(block)
+    }
+
+    /// Maps and returns a dedicated `BlockPtr` with a memory region aligned to `align`.
+    unsafe fn map_block(&self, size: usize, align: usize) -> Option<BlockPtr> {
+        // The memory region follows the header unless a larger alignment moves the block
+        // further into the mapping, by at most `align - BLOCK_META_SIZE` bytes.
+        let extra = align.saturating_sub(BLOCK_META_SIZE);
+        let len = BLOCK_META_SIZE.checked_add(size)?.checked_add(extra)?;
+        let len = util::pad_to_align(len, *PAGE_SIZE).ok()?.size();
+        let ptr = util::mmap(len)?;
+
+        // Place the block so its memory region is aligned.
+        let start = ptr.as_ptr() as usize;
+        let offset = util::align_up(start + BLOCK_META_SIZE, align) - BLOCK_META_SIZE - start;
+        if unlikely(offset > u32::MAX as usize) {
+            util::munmap(ptr, len);
+            return None;
+        }
+        let block_ptr = Unique::new_unchecked(ptr.as_ptr().add(offset));
+        let block = BlockPtr::new_mmapped(block_ptr, len - offset - BLOCK_META_SIZE, offset as u32);
+        self.mappings.map(len);
+        dprintln!("[mmap]: {} at {:p}", block.as_ref(), block);
+        Some(block)
+    }
+
+    /// Maps and returns a dedicated `BlockPtr` with a memory region aligned to `align`,
+    /// which ends right before an inaccessible guard page at the end of the mapping.
+    /// The region starts at the last aligned address leaving room for `size` bytes, so the
+    /// first byte past `size` faults as long as `size` is a multiple of the alignment.
+    /// NOTE: Like with Electric Fence's `EF_ALIGNMENT`, overflows into the remaining slack of
+    /// up to `align - 1` bytes are not detected, e.g. up to 15 bytes for `malloc`, which
+    /// returns memory aligned to 16 bytes. The alignment is at least that of the header.
+    unsafe fn map_guarded_block(&self, size: usize, align: usize) -> Option<BlockPtr> {
+        let page = *PAGE_SIZE;
+        let align = cmp::max(align, mem::align_of::<Block>());
+        // Worst case space in front of the memory region for the header and alignment.
+        let extra = BLOCK_META_SIZE + align - 1;
+        let data_len = util::pad_to_align(size.checked_add(extra)?, page)
+            .ok()?
+            .size();
+        let len = data_len.checked_add(page)?;
+        let ptr = util::mmap(len)?;
+
+        let start = ptr.as_ptr() as usize;
+        let guard = start + data_len;
+        let region = (guard - size) & !(align - 1);
+        let offset = region - BLOCK_META_SIZE - start;
+        let guard_ptr = Unique::new_unchecked(guard as *mut c_void);
+        if unlikely(offset > u32::MAX as usize || !util::mprotect_none(guard_ptr, page)) {
+            util::munmap(ptr, len);
+            return None;
+        }
+        let block_ptr = Unique::new_unchecked(ptr.as_ptr().add(offset));
+        let block = BlockPtr::new_guarded(block_ptr, guard - region, offset as u32);
+        self.mappings.map(len);
+        dprintln!("[mmap]: guarded {} at {:p}", block.as_ref(), block);
+        Some(block)
+    }
+
+    /// Resizes the mapping of the given mmapped `BlockPtr` to fit `size`.
+    /// Returns `None` if the mapping could not be resized.
+    unsafe fn remap_block(&self, block: BlockPtr, size: usize) -> Option<BlockPtr> {
+        // Moving the mapping would break alignment of offset blocks,
+        // guard pages would end up in the middle of the mapping.
+        if block.offset() != 0 || block.is_guarded() {
+            return None;
+        }
+        let len = util::pad_to_align(BLOCK_META_SIZE.checked_add(size)?, *PAGE_SIZE)
+            .ok()?
+            .size();
+        if len == block.mapping_size() {
+            return Some(block);
+        }
+        let old_len = block.mapping_size();
+        let ptr = util::mremap(block.mapping(), old_len, len)?;
+        self.mappings.remap(old_len, len);
+        let block = BlockPtr::remapped(ptr, len - BLOCK_META_SIZE);
+        dprintln!("[mremap]: {} at {:p}", block.as_ref(), block);
+        Some(block)
+    }
+
+    /// Fills the first `size` bytes of a newly allocated `BlockPtr`
+    /// with the alloc junk byte, if enabled.
+    #[inline]
+    unsafe fn junk_alloc(&self, block: BlockPtr, size: usize) {
+        if let Some(byte) = self.config.alloc_junk() {
+            intrinsics::volatile_set_memory(block.mem_region().as_ptr(), byte, size);
+        }
+    }
+
+    /// Fills the memory region of a freed heap `BlockPtr` with the free junk byte,
+    /// if enabled. The free list links are left intact.
+    #[inline]
+    unsafe fn junk_free(&self, block: BlockPtr) {
+        if let Some(byte) = self.config.free_junk() {
+            block.fill_unlinked(byte);
+        }
+    }
+
+    /// Returns the memory region of a newly allocated `BlockPtr` holding `size` requested
+    /// bytes, after recording it with the heap profiler if it is sampled.
+    #[inline]
+    unsafe fn sampled(&self, mut block: BlockPtr, size: usize) -> *mut u8 {
+        let ptr = block.mem_region().cast::<u8>().as_ptr();
+        if unlikely(profile::active()) && profile::sample(ptr, size) {
+            block.set_sampled(true);
+        }
+        ptr
+    }
+
+    /// Returns the memory region of a `BlockPtr` resized from the user pointer `old`.
+    /// Resized blocks are sampled anew like moved ones.
+    #[inline]
+    unsafe fn resampled(&self, old: Unique<c_void>, mut block: BlockPtr, size: usize) -> *mut u8 {
+        if unlikely(block.is_sampled()) {
+            profile::release(old.cast::<u8>().as_ptr());
+            block.set_sampled(false);
+        }
+        self.sampled(block, size)
+    }
+
+    /// Stops recording the given sampled `BlockPtr` with the heap profiler.
+    #[cold]
+    unsafe fn unsample(&self, block: &mut BlockPtr) {
+        profile::release(block.mem_region().cast::<u8>().as_ptr());
+        block.set_sampled(false);
+    }
+
+    /// Returns the given `BlockPtr` either to the OS if it has
+    /// a dedicated mapping, to the thread cache or to the allocator otherwise.
+    unsafe fn free_block(&self, mut block: BlockPtr) {
+        if unlikely(block.is_sampled()) {
+            self.unsample(&mut block);
+        }
+        if block.is_mmapped() {
+            stats::record_free(block.size());
+            dprintln!("[munmap]: {} at {:p}", block.as_ref(), block);
+            // The header must not be read anymore once unmapped.
+            self.mappings.unmap(block.mapping_size());
+            util::munmap(block.mapping(), block.mapping_size());
+            return;
+        }
+        // Blocks freed twice must be left intact to be detected.
+        if !block.is_free() && !block.is_cached() {
+            stats::record_free(block.size());
+            self.junk_free(block);
+        }
+        let max = self.config.quarantine();
+        if unlikely(max != 0) {
+            self.quarantine.lock().push(block);
+            self.evict_quarantined(max);
+            return;
+        }
+        if !TCache::get().push(self, block) {
+            self.release_block(block);
+        }
+    }
+
+    /// Releases the oldest quarantined blocks until at most `max` bytes are quarantined,
+    /// after checking them for writes after free.
+    #[cold]
+    unsafe fn evict_quarantined(&self, max: usize) {
+        loop {
+            // The lock is not held while releasing, since the error handler may allocate.
+            let block = match self.quarantine.lock().evict(max) {
+                Some(block) => block,
+                None => break,
+            };
+            if let Some(offset) = quarantine::modified(block) {
+                dprintln!(
+                    "[quarantine]: {} modified at offset {}",
+                    block.as_ref(),
+                    offset
+                );
+                self.report_error(
+                    "free",
+                    ErrorKind::UseAfterFree,
+                    block.mem_region().as_ptr(),
+                    Some(block.size()),
+                );
+            }
+            self.release_block(block);
+        }
+    }
+
+    /// Releases all quarantined blocks after checking them for writes after free.
+    pub fn flush_quarantine(&self) {
+        unsafe { self.evict_quarantined(0) };
+    }
+
+    /// Releases the given `BlockPtr` back to the allocator.
+    /// NOTE: The memory is returned to the OS if it is adjacent to program break.
+    unsafe fn release_block(&self, block: BlockPtr) {
+        // Blocks are always released to the arena owning them.
+        let mut heap = match self.arenas.get(block.arena()) {
+            Some(heap) => heap.lock(),
+            None => {
+                let ptr = block.mem_region().as_ptr();
+                self.report_error("free", ErrorKind::InvalidArena, ptr, None);
+                return;
+            }
+        };
+
+        #[cfg(feature = "debug")]
+        {
+            heap.debug();
+        }
+        if let Err(kind) = heap.release(block, &self.config) {
+            drop(heap);
+            if kind == ErrorKind::DoubleFree {
+                stats::record_double_free();
+            }
+            self.report_error(
+                "free",
+                kind,
+                block.mem_region().as_ptr(),
+                Some(block.size()),
+            );
+        }
+    }
+}
+
+/// Writes the call stack to stderr, resolving return addresses to their objects.
+fn write_backtrace() {
+    let mut frames = [0; profile::MAX_DEPTH];
+    let depth = unsafe { profile::backtrace(&mut frames) };
+    let mut out = FdWriter(libc::STDERR_FILENO);
+    for (idx, frame) in frames[..depth].iter().enumerate() {
+        let _ = write!(out, "    #{:<2} {:#x}", idx, frame);
+        let _ = leaks::write_object(&mut out, *frame);
+        let _ = writeln!(out);
+    }
+}
+
+impl Default for Collam {
+    fn default() -> Self {
+        Collam::new()
+    }
+}
+
+impl Drop for Collam {
+    fn drop(&mut self) {
+        // Blocks cached by other threads are flushed on their exit,
+        // only the current thread can be taken care of here.
+        let cache = unsafe { TCache::get() };
+        if cache.owns(self) {
+            unsafe { cache.flush() };
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Collam {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout, false)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout, true)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
@@ -127,24 +596,23 @@ unsafe impl GlobalAlloc for Collam {
                 Some(b) => b,
                 None => return,
             };
-            if unlikely(!block.as_ref().verify()) {
-                eprintln!("free(): Unable to verify {} at {:p}", block.as_ref(), block);
+            if unlikely(!self.check_in_use(block, "free")) {
                 return;
             }
-            // Add freed block back to heap structure.
-            self.release_block(block)
+            // Add freed block back to heap structure or unmap it.
+            self.free_block(block)
         }
     }
 
-    unsafe fn realloc(&self, ptr: *mut u8, _layout: Layout, new_size: usize) -> *mut u8 {
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
         let ptr = match Unique::new(ptr) {
             Some(p) => p.cast::<c_void>(),
             None => return null_mut(),
         };
 
         dprintln!("[libcollam.so]: realloc(ptr={:p}, size={})", ptr, new_size);
+        stats::record_realloc();
 
-        // FIXME: Alignment  to old layout needed?
         let new_layout = match util::pad_to_scalar(new_size) {
             Ok(l) => l,
             Err(_) => return null_mut(),
@@ -155,86 +623,235 @@ unsafe impl GlobalAlloc for Collam {
             None => return null_mut(),
         };
 
-        if unlikely(!old_block.as_ref().verify()) {
-            eprintln!(
-                "realloc(): Unable to verify {} at {:p}",
-                old_block.as_ref(),
-                old_block
-            );
+        if unlikely(!self.check_in_use(old_block, "realloc")) {
             return null_mut();
         }
 
+        // Resize dedicated mappings as long as the size is above the threshold.
+        if old_block.is_mmapped() && new_layout.size() >= self.config.mmap_threshold() {
+            let old_size = old_block.size();
+            if let Some(block) = self.remap_block(old_block, new_layout.size()) {
+                stats::record_resize(old_size, block.size());
+                return self.resampled(ptr, block, new_size);
+            }
+        }
+
         // Shrink allocated block if size is smaller.
-        if new_layout.size() < old_block.size() {
+        if new_layout.size() < old_block.size() && !old_block.is_mmapped() {
+            let old_size = old_block.size();
             if let Some(rem_block) = old_block.shrink(new_layout.size()) {
+                stats::record_resize(old_size, old_block.size());
+                self.junk_free(rem_block);
                 self.release_block(rem_block);
             }
-            return ptr.cast::<u8>().as_ptr();
+            return self.resampled(ptr, old_block, new_size);
         }
 
         // Just return pointer if size didn't change.
-        if new_layout.size() == old_block.size() {
-            return ptr.cast::<u8>().as_ptr();
+        if new_layout.size() == old_block.size() && !old_block.is_mmapped() {
+            return self.resampled(ptr, old_block, new_size);
         }
 
-        // Allocate new region to fit size.
-        let new_ptr = self.alloc(new_layout).cast::<c_void>();
-        let copy_size = cmp::min(new_layout.size(), old_block.size());
+        // Allocate new region to fit size, preserving the original alignment.
+        let new_ptr = self
+            .alloc(Layout::from_size_align_unchecked(new_size, layout.align()))
+            .cast::<c_void>();
+        // The old block stays sampled, since it is still owned by the caller.
+        if new_ptr.is_null() {
+            return null_mut();
+        }
+        // Guarded blocks may end right after `new_size` bytes.
+        let copy_size = cmp::min(new_size, old_block.size());
         intrinsics::volatile_copy_nonoverlapping_memory(new_ptr, ptr.as_ptr(), copy_size);
-        // Add old block back to heap structure.
-        self.release_block(old_block);
+        // Add old block back to heap structure or unmap it.
+        self.free_block(old_block);
         new_ptr.cast::<u8>()
     }
 }
 
-/// Requests memory for the specified size from kernel
-/// and returns a `BlockPtr` to the newly created block or `None` if not possible.
-/// Marked as unsafe because it is not thread safe.
-unsafe fn request_block(min_size: usize) -> Option<BlockPtr> {
-    let size = util::pad_to_align(BLOCK_META_SIZE + min_size, *PAGE_SIZE)
-        .ok()?
-        .size();
-    Some(BlockPtr::new(
-        util::sbrk(size as isize)?,
-        size - BLOCK_META_SIZE,
-    ))
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::alloc::block::BLOCK_LINKS_SIZE;
     use crate::util;
     use core::intrinsics::write_bytes;
 
+    fn block_of(ptr: *mut u8) -> BlockPtr {
+        let ptr = Unique::new(ptr.cast::<c_void>()).expect("got null pointer");
+        let block = BlockPtr::from_mem_region(ptr).expect("unable to get block");
+        assert!(block.as_ref().verify());
+        block
+    }
+
     #[test]
-    fn test_request_block() {
+    fn test_collam_alloc_ok() {
         unsafe {
-            let block = request_block(256).expect("unable to request block");
-            let brk = block.next_potential_block().as_ptr();
-            assert_eq!(brk, util::sbrk(0).expect("sbrk(0) failed").as_ptr());
+            let collam = Collam::new();
+            let layout = util::pad_to_scalar(123).expect("unable to align layout");
+            let ptr = collam.alloc(layout);
+            assert!(!ptr.is_null());
+            write_bytes(ptr, 1, 123);
+            collam.dealloc(ptr, layout);
         }
     }
 
     #[test]
-    fn test_request_block_split() {
+    fn test_collam_alloc_aligned() {
         unsafe {
-            let rem_block = request_block(256)
-                .expect("unable to request block")
-                .shrink(128)
-                .expect("unable to split block");
-            let brk = rem_block.next_potential_block().as_ptr();
-            assert_eq!(brk, util::sbrk(0).expect("sbrk(0) failed").as_ptr());
+            let collam = Collam::new();
+            for align in [32, 64, 128, 4096, 65536].iter() {
+                for size in [1, 24, 100, 5000].iter() {
+                    let layout = Layout::from_size_align(*size, *align).expect("invalid layout");
+                    let ptr = collam.alloc(layout);
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % align, 0, "size={}, align={}", size, align);
+                    write_bytes(ptr, 1, *size);
+                    collam.dealloc(ptr, layout);
+                }
+            }
         }
     }
 
     #[test]
-    fn test_collam_alloc_ok() {
+    fn test_collam_alloc_zeroed_aligned() {
         unsafe {
             let collam = Collam::new();
-            let layout = util::pad_to_scalar(123).expect("unable to align layout");
+            let layout = Layout::from_size_align(300, 256).expect("invalid layout");
+            let ptr = collam.alloc_zeroed(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 256, 0);
+            assert!((0..300).all(|i| *ptr.add(i) == 0));
+            collam.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_alloc_mmapped() {
+        unsafe {
+            let collam = Collam::new();
+            let size = MMAP_THRESHOLD_DEFAULT * 4;
+            let layout = Layout::from_size_align(size, 16).expect("invalid layout");
             let ptr = collam.alloc(layout);
             assert!(!ptr.is_null());
-            write_bytes(ptr, 1, 123);
+            let block = block_of(ptr);
+            assert!(block.is_mmapped());
+            assert_eq!(block.offset(), 0);
+            assert_eq!(block.mapping_size() % *PAGE_SIZE, 0);
+            write_bytes(ptr, 1, size);
+            collam.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_alloc_mmapped_aligned() {
+        unsafe {
+            let collam = Collam::new();
+            for align in [64, 4096, 1 << 20].iter() {
+                let size = MMAP_THRESHOLD_DEFAULT;
+                let layout = Layout::from_size_align(size, *align).expect("invalid layout");
+                let ptr = collam.alloc(layout);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0);
+                assert!(block_of(ptr).is_mmapped());
+                write_bytes(ptr, 1, size);
+                collam.dealloc(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_collam_alloc_mmapped_aligned_page_multiple() {
+        unsafe {
+            let collam = Collam::new();
+            // Sizes filling whole pages with the header leave no space for the alignment.
+            let size = MMAP_THRESHOLD_DEFAULT + *PAGE_SIZE - BLOCK_META_SIZE;
+            for align in [64, 4096].iter() {
+                let layout = Layout::from_size_align(size, *align).expect("invalid layout");
+                let ptr = collam.alloc(layout);
+                assert_eq!(ptr as usize % align, 0);
+                let block = block_of(ptr);
+                assert!(block.is_mmapped());
+                assert!(block.size() >= size);
+                write_bytes(ptr, 1, block.size());
+                collam.dealloc(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_collam_mmap_threshold() {
+        unsafe {
+            let collam = Collam::new();
+            collam.config().set_mmap_threshold(1024);
+            assert_eq!(collam.config().mmap_threshold(), 1024);
+            let layout = util::pad_to_scalar(2048).expect("unable to align layout");
+            let ptr = collam.alloc(layout);
+            assert!(block_of(ptr).is_mmapped());
+            collam.dealloc(ptr, layout);
+
+            collam.config().set_mmap_threshold(usize::MAX);
+            let ptr = collam.alloc(layout);
+            assert!(!block_of(ptr).is_mmapped());
+            collam.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_arenas() {
+        unsafe {
+            let collam = Collam::new();
+            collam.config().set_arena_max(2);
+            assert_eq!(collam.arena_max(), 2);
+            let layout = util::pad_to_scalar(2048).expect("unable to align layout");
+            let ptr = collam.alloc(layout);
+            let arena = block_of(ptr).arena();
+
+            // The next thread is bound to the other arena, blocks freed
+            // by this thread are still released to it.
+            let other = std::thread::scope(|s| {
+                s.spawn(|| collam.alloc(layout) as usize)
+                    .join()
+                    .expect("thread panicked")
+            }) as *mut u8;
+            let block = block_of(other);
+            assert_eq!(block.arena(), 1 - arena);
+            collam.dealloc(other, layout);
+            assert!(block.is_free());
+            assert_eq!(
+                collam.arenas.get(1 - arena).unwrap().lock().bins().count(),
+                1
+            );
+            collam.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_realloc_mmapped() {
+        unsafe {
+            let collam = Collam::new();
+            let size = MMAP_THRESHOLD_DEFAULT * 2;
+            let layout = util::pad_to_scalar(size).expect("unable to align layout");
+            let ptr = collam.alloc(layout);
+            write_bytes(ptr, 7, size);
+
+            // Grow with mremap
+            let ptr = collam.realloc(ptr, layout, size * 8);
+            assert!(!ptr.is_null());
+            assert!(block_of(ptr).is_mmapped());
+            assert!(block_of(ptr).size() >= size * 8);
+            assert!((0..size).all(|i| *ptr.add(i) == 7));
+            write_bytes(ptr, 8, size * 8);
+
+            // Shrink below threshold moves the block back to the heap
+            let ptr = collam.realloc(ptr, layout, 512);
+            assert!(!ptr.is_null());
+            assert!(!block_of(ptr).is_mmapped());
+            assert!((0..512).all(|i| *ptr.add(i) == 8));
+
+            // Grow above threshold moves the block to a dedicated mapping
+            let ptr = collam.realloc(ptr, layout, size);
+            assert!(block_of(ptr).is_mmapped());
+            assert!((0..512).all(|i| *ptr.add(i) == 8));
             collam.dealloc(ptr, layout);
         }
     }
@@ -263,6 +880,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_collam_realloc_keeps_alignment() {
+        unsafe {
+            let collam = Collam::new();
+            let layout = Layout::from_size_align(64, 4096).expect("invalid layout");
+            let ptr = collam.alloc(layout);
+            assert!(!ptr.is_null());
+            write_bytes(ptr, 3, 64);
+
+            let ptr = collam.realloc(ptr, layout, 10000);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 4096, 0);
+            assert!((0..64).all(|i| *ptr.add(i) == 3));
+
+            let ptr = collam.realloc(ptr, layout, 32);
+            assert_eq!(ptr as usize % 4096, 0);
+            collam.dealloc(ptr, layout);
+        }
+    }
+
     #[test]
     fn test_collam_realloc_smaller_size() {
         unsafe {
@@ -347,4 +984,502 @@ mod tests {
             collam.dealloc(ptr, layout);
         }
     }
+
+    #[test]
+    fn test_collam_trim() {
+        unsafe {
+            let collam = Collam::new();
+            collam.config().set_arena_max(1);
+            collam.config().set_trim_threshold(usize::MAX);
+            let layout = util::pad_to_scalar(256 * 1024).expect("unable to align layout");
+            collam.config().set_mmap_threshold(usize::MAX);
+            let ptr = collam.alloc(layout);
+            let small = util::pad_to_scalar(64).expect("unable to align layout");
+            let ptr2 = collam.alloc(small);
+            collam.dealloc(ptr, layout);
+            collam.dealloc(ptr2, small);
+            assert!(block_of(ptr2).is_cached());
+
+            let brk_before = brk();
+            assert!(collam.trim(0));
+            assert!(brk() < brk_before);
+        }
+    }
+
+    #[test]
+    fn test_collam_info() {
+        unsafe {
+            let collam = Collam::new();
+            collam.config().set_arena_max(1);
+            collam.config().set_trim_threshold(usize::MAX);
+            let layout = util::pad_to_scalar(4096).expect("unable to align layout");
+            let ptr = collam.alloc(layout);
+            let ptr2 = collam.alloc(layout);
+            collam.dealloc(ptr, layout);
+
+            let info = collam.info();
+            // Only the first arena is in use.
+            let arena = collam.arena_info(0).expect("missing arena");
+            assert_eq!((arena.arena, arena.free), (info.arena, info.free));
+            assert!(info.arena > 0);
+            assert_eq!(info.in_use + info.free, info.arena);
+            assert!(info.free >= 4096 && info.free_blocks >= 1);
+            assert!(info.in_use >= 4096);
+            assert!(info.releasable > 0 && info.releasable <= info.free);
+
+            // Dedicated mappings are counted separately.
+            let big = util::pad_to_scalar(MMAP_THRESHOLD_DEFAULT).expect("unable to align layout");
+            let ptr3 = collam.alloc(big);
+            let info = collam.info();
+            assert_eq!(info.mmapped_blocks, 1);
+            assert!(info.mmapped > MMAP_THRESHOLD_DEFAULT);
+            collam.dealloc(ptr3, big);
+            let info = collam.info();
+            assert_eq!((info.mmapped_blocks, info.mmapped), (0, 0));
+            assert_eq!(info.max_mmapped_blocks, 1);
+            collam.dealloc(ptr2, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_read_env() {
+        unsafe {
+            let collam = Collam::new();
+            libc::setenv(
+                b"COLLAM_CONF\0".as_ptr().cast(),
+                b"arena_max=1,top_pad=64k\0".as_ptr().cast(),
+                1,
+            );
+            let layout = Layout::from_size_align_unchecked(64, 16);
+            let ptr = collam.alloc(layout);
+            libc::unsetenv(b"COLLAM_CONF\0".as_ptr().cast());
+            assert_eq!(collam.config().arena_max(), 1);
+            assert_eq!(collam.config().top_pad(), 64 * 1024);
+
+            // The environment is only read once.
+            collam.config().set_top_pad(0);
+            collam.read_env();
+            assert_eq!(collam.config().top_pad(), 0);
+            collam.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_stats() {
+        unsafe {
+            let collam = Collam::new();
+            let before = stats::snapshot();
+            let layout = Layout::from_size_align_unchecked(100, 16);
+            let ptr = collam.alloc(layout);
+            let stats = stats::snapshot();
+            assert_eq!(stats.allocations, before.allocations + 1);
+            assert_eq!(stats.requested_bytes, before.requested_bytes + 100);
+            let granted = stats.granted_bytes - before.granted_bytes;
+            assert!(granted >= 112);
+            assert_eq!(stats.in_use, before.in_use + granted);
+            assert!(stats.peak_in_use >= stats.in_use);
+
+            // Moving a block counts as allocation and free.
+            let ptr = collam.realloc(ptr, layout, 4096);
+            let stats = stats::snapshot();
+            assert_eq!(stats.reallocs, before.reallocs + 1);
+            assert_eq!(stats.allocations, before.allocations + 2);
+            assert_eq!(stats.frees, before.frees + 1);
+            assert!(stats.splits > before.splits);
+
+            collam.dealloc(ptr, layout);
+            let stats = stats::snapshot();
+            assert_eq!(stats.frees, before.frees + 2);
+            assert_eq!(stats.in_use, before.in_use);
+        }
+    }
+
+    #[test]
+    fn test_collam_profile() {
+        unsafe {
+            let collam = Collam::new();
+            profile::reset();
+            profile::set_interval(1);
+            let layout = Layout::from_size_align_unchecked(100, 16);
+            let ptr = collam.alloc(layout);
+            assert!(block_of(ptr).is_sampled());
+            // Resized in place and sampled anew.
+            let ptr = collam.realloc(ptr, layout, 100);
+            assert!(block_of(ptr).is_sampled());
+            let moved = collam.realloc(ptr, layout, MMAP_THRESHOLD_DEFAULT);
+            assert!(block_of(moved).is_sampled());
+
+            // Blocks which could not be resized are still live and stay in the profile.
+            let ptr = collam.alloc(layout);
+            assert!(collam.realloc(ptr, layout, 1 << 60).is_null());
+            profile::set_interval(0);
+            assert!(block_of(ptr).is_sampled());
+            let mut out = std::string::String::new();
+            profile::write(&mut out).expect("unable to write profile");
+            let expected = std::format!("[     1: {:>8}]", 100);
+            assert!(out.contains(&expected), "{}", out);
+            collam.dealloc(ptr, layout);
+
+            out.clear();
+            profile::write(&mut out).expect("unable to write profile");
+            let expected = std::format!("[     4: {:>8}]", 300 + MMAP_THRESHOLD_DEFAULT);
+            assert!(out.contains(&expected), "{}", out);
+            collam.dealloc(moved, layout);
+            out.clear();
+            profile::write(&mut out).expect("unable to write profile");
+            assert!(
+                out.starts_with("heap profile:      0:        0 ["),
+                "{}",
+                out
+            );
+            profile::reset();
+        }
+    }
+
+    fn brk() -> usize {
+        unsafe { util::sbrk(0).expect("sbrk(0) failed").as_ptr() as usize }
+    }
+
+    #[test]
+    fn test_collam_trim_threshold() {
+        unsafe {
+            let collam = Collam::new();
+            collam.config().set_arena_max(1);
+            let layout = util::pad_to_scalar(64 * 1024).expect("unable to align layout");
+
+            collam.config().set_trim_threshold(usize::MAX);
+            let ptr = collam.alloc(layout);
+            let brk_before = brk();
+            collam.dealloc(ptr, layout);
+            assert_eq!(brk(), brk_before);
+
+            collam.config().set_trim_threshold(32 * 1024);
+            let ptr = collam.alloc(layout);
+            let brk_before = brk();
+            collam.dealloc(ptr, layout);
+            assert!(brk() < brk_before);
+        }
+    }
+
+    #[test]
+    fn test_collam_top_pad() {
+        unsafe {
+            let collam = Collam::new();
+            collam.config().set_arena_max(1);
+            collam.config().set_top_pad(1024 * 1024);
+            let layout = util::pad_to_scalar(16).expect("unable to align layout");
+            let brk_before = brk();
+            let ptr = collam.alloc(layout);
+            assert!(brk() - brk_before >= 1024 * 1024);
+
+            // The padding serves the following allocations.
+            let brk_before = brk();
+            let layout2 = util::pad_to_scalar(512 * 1024).expect("unable to align layout");
+            collam.config().set_mmap_threshold(usize::MAX);
+            let ptr2 = collam.alloc(layout2);
+            assert_eq!(brk(), brk_before);
+            collam.dealloc(ptr2, layout2);
+            collam.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_perturb() {
+        unsafe {
+            let collam = Collam::new();
+            collam.config().set_perturb(0xAA);
+            let layout = util::pad_to_scalar(256).expect("unable to align layout");
+            let ptr = collam.alloc(layout);
+            let region = core::slice::from_raw_parts(ptr, 256);
+            assert!(region.iter().all(|b| *b == 0x55));
+
+            // Freed memory is filled, apart from the free list links and the footer.
+            collam.dealloc(ptr, layout);
+            let block = block_of(ptr);
+            assert!(region[16..block.size() - 8].iter().all(|b| *b == 0xAA));
+
+            // Zeroed allocations are not affected.
+            let ptr = collam.alloc_zeroed(layout);
+            assert!(core::slice::from_raw_parts(ptr, 256)
+                .iter()
+                .all(|b| *b == 0));
+            collam.dealloc(ptr, layout);
+
+            // Fresh mappings of zeroed allocations are neither filled nor cleared,
+            // so their pages are not faulted in.
+            let size = MMAP_THRESHOLD_DEFAULT * 8;
+            let layout = util::pad_to_scalar(size).expect("unable to align layout");
+            let ptr = collam.alloc_zeroed(layout);
+            let page = *PAGE_SIZE;
+            let start = util::align_up(ptr as usize, page) + page;
+            let pages = (ptr as usize + size - start) / page;
+            let mut resident = std::vec![0u8; pages];
+            assert_eq!(
+                libc::mincore(start as *mut c_void, pages * page, resident.as_mut_ptr()),
+                0
+            );
+            assert!(resident.iter().all(|r| r & 1 == 0));
+            assert_eq!(*ptr.add(size - 1), 0);
+            collam.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_junk() {
+        unsafe {
+            let collam = Collam::new();
+            collam.config().set_alloc_junk(Some(0xA5));
+            collam.config().set_free_junk(Some(0x5A));
+            // Blocks above the thread cache limit are released to the free bins right away.
+            let size = 4096;
+            let layout = util::pad_to_scalar(size).expect("unable to align layout");
+            let ptrs: std::vec::Vec<_> = (0..3).map(|_| collam.alloc(layout)).collect();
+            for ptr in ptrs.iter() {
+                assert!(core::slice::from_raw_parts(*ptr, size)
+                    .iter()
+                    .all(|b| *b == 0xA5));
+            }
+
+            // The remainder of a shrunk block is filled as well.
+            let ptr = collam.realloc(ptrs[1], layout, 64);
+            assert_eq!(ptr, ptrs[1]);
+            let rem = BLOCK_META_SIZE + block_of(ptr).size() + BLOCK_LINKS_SIZE;
+            let region = core::slice::from_raw_parts(ptr, size);
+            assert!(region[rem..size - 8].iter().all(|b| *b == 0x5A));
+
+            // Freed blocks are filled apart from the links, so they can be reused.
+            collam.dealloc(ptrs[0], layout);
+            let block = block_of(ptrs[0]);
+            assert!(block.is_free());
+            let region = core::slice::from_raw_parts(ptrs[0], block.size());
+            assert!(region[BLOCK_LINKS_SIZE..block.size() - 8]
+                .iter()
+                .all(|b| *b == 0x5A));
+            assert_eq!(collam.alloc(layout), ptrs[0]);
+            for ptr in ptrs.iter() {
+                collam.dealloc(*ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_collam_check_action_abort() {
+        unsafe {
+            let collam = Collam::new();
+            collam
+                .config()
+                .set_check_action(CHECK_ACTION_PRINT | CHECK_ACTION_ABORT);
+            let layout = util::pad_to_scalar(32).expect("unable to align layout");
+            let ptr = collam.alloc(layout);
+            ptr.sub(BLOCK_META_SIZE).write_bytes(0, BLOCK_META_SIZE);
+
+            let pid = libc::fork();
+            assert!(pid >= 0, "fork failed");
+            if pid == 0 {
+                collam.dealloc(ptr, layout);
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert!(libc::WIFSIGNALED(status));
+            assert_eq!(libc::WTERMSIG(status), libc::SIGABRT);
+
+            // Without the abort flag the corruption is only reported.
+            collam.config().set_check_action(0);
+            collam.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_error_handler() {
+        static ERRORS: spin::Mutex<std::vec::Vec<(ErrorKind, usize)>> =
+            spin::Mutex::new(std::vec::Vec::new());
+        extern "C" fn handler(kind: ErrorKind, ptr: *mut c_void) {
+            ERRORS.lock().push((kind, ptr as usize));
+        }
+
+        unsafe {
+            let collam = Collam::new();
+            collam.config().set_check_action(0);
+            collam.config().set_error_handler(Some(handler));
+            let layout = util::pad_to_scalar(64).expect("unable to align layout");
+            let ptr = collam.alloc(layout);
+            collam.dealloc(ptr, layout);
+            collam.dealloc(ptr, layout);
+            assert_eq!(collam.realloc(ptr, layout, 128), null_mut());
+            let mut region = [0u8; 64];
+            let invalid = region.as_mut_ptr().add(BLOCK_META_SIZE);
+            collam.dealloc(invalid, layout);
+            collam.config().set_error_handler(None);
+            collam.dealloc(ptr, layout);
+
+            assert_eq!(
+                *ERRORS.lock(),
+                [
+                    (ErrorKind::DoubleFree, ptr as usize),
+                    (ErrorKind::DoubleFree, ptr as usize),
+                    (ErrorKind::InvalidPointer, invalid as usize),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_collam_guard() {
+        unsafe fn faults(ptr: *mut u8) -> bool {
+            let pid = libc::fork();
+            assert!(pid >= 0, "fork failed");
+            if pid == 0 {
+                ptr.write_volatile(0);
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSEGV
+        }
+
+        unsafe {
+            let collam = Collam::new();
+            collam.config().set_guard_threshold(0);
+
+            // The first byte past the request faults.
+            let layout = Layout::from_size_align_unchecked(104, 8);
+            let ptr = collam.alloc(layout);
+            let block = block_of(ptr);
+            assert!(block.is_mmapped() && block.is_guarded());
+            assert_eq!(block.size(), 104);
+            assert_eq!((ptr as usize + 104) % *PAGE_SIZE, 0);
+            write_bytes(ptr, 0xAB, 104);
+            assert!(faults(ptr.add(104)));
+            collam.dealloc(ptr, layout);
+
+            // Overflows into the alignment slack are not detected.
+            let layout = Layout::from_size_align_unchecked(100, 16);
+            let ptr = collam.alloc(layout);
+            let block = block_of(ptr);
+            assert!(block.is_guarded());
+            // The memory region ends right before the guard page.
+            let end = ptr as usize + block.size();
+            assert_eq!(end % *PAGE_SIZE, 0);
+            assert_eq!(block.size(), 112);
+            assert!(!faults(ptr.add(100)));
+            assert!(faults(ptr.add(block.size())));
+            write_bytes(ptr, 0xAB, 100);
+
+            // Resized allocations move to a new guarded mapping.
+            let ptr = collam.realloc(ptr, layout, 5000);
+            let block = block_of(ptr);
+            assert!(block.is_guarded());
+            assert_eq!((ptr as usize + block.size()) % *PAGE_SIZE, 0);
+            assert!(core::slice::from_raw_parts(ptr, 100)
+                .iter()
+                .all(|b| *b == 0xAB));
+            collam.dealloc(ptr, layout);
+
+            // Aligned allocations end before the guard page as close as possible.
+            let layout = Layout::from_size_align_unchecked(100, 256);
+            let ptr = collam.alloc(layout);
+            assert_eq!(ptr as usize % 256, 0);
+            let block = block_of(ptr);
+            assert!(block.is_guarded() && block.size() >= 100 && block.size() < 100 + 256);
+            collam.dealloc(ptr, layout);
+
+            // Allocations below the threshold are not guarded.
+            collam.config().set_guard_threshold(4096);
+            let ptr = collam.alloc(layout);
+            assert!(!block_of(ptr).is_mmapped());
+            collam.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_quarantine() {
+        static ERRORS: spin::Mutex<std::vec::Vec<(ErrorKind, usize)>> =
+            spin::Mutex::new(std::vec::Vec::new());
+        extern "C" fn handler(kind: ErrorKind, ptr: *mut c_void) {
+            ERRORS.lock().push((kind, ptr as usize));
+        }
+
+        unsafe {
+            let collam = Collam::new();
+            collam.config().set_check_action(0);
+            collam.config().set_error_handler(Some(handler));
+            let layout = util::pad_to_scalar(64).expect("unable to align layout");
+            collam.config().set_quarantine(4 * layout.size());
+            let ptrs: std::vec::Vec<_> = (0..6).map(|_| collam.alloc(layout)).collect();
+            collam.dealloc(ptrs[0], layout);
+            assert_eq!(block_of(ptrs[0]).state(), Some(BlockState::Quarantined));
+            assert!(core::slice::from_raw_parts(ptrs[0], 64)[BLOCK_LINKS_SIZE..]
+                .iter()
+                .all(|b| *b == quarantine::QUARANTINE_POISON));
+
+            // Quarantined blocks are neither reused nor freed again.
+            *ptrs[0].add(32) = 1;
+            collam.dealloc(ptrs[0], layout);
+            let ptr = collam.alloc(layout);
+            assert!(!ptrs.contains(&ptr));
+            collam.dealloc(ptr, layout);
+
+            // Exceeding the limit releases the oldest blocks and checks them.
+            for ptr in ptrs[1..].iter() {
+                collam.dealloc(*ptr, layout);
+            }
+            assert_eq!(collam.quarantine.lock().bytes(), 4 * layout.size());
+            assert_eq!(block_of(ptrs[2]).state(), Some(BlockState::Quarantined));
+            assert_eq!(
+                *ERRORS.lock(),
+                [
+                    (ErrorKind::DoubleFree, ptrs[0] as usize),
+                    (ErrorKind::UseAfterFree, ptrs[0] as usize),
+                ]
+            );
+            *ptrs[5].add(63) = 1;
+            collam.config().set_quarantine(0);
+            collam.flush_quarantine();
+            assert_eq!(collam.quarantine.lock().bytes(), 0);
+            assert_eq!(
+                ERRORS.lock().last(),
+                Some(&(ErrorKind::UseAfterFree, ptrs[5] as usize))
+            );
+            collam.config().set_error_handler(None);
+        }
+    }
+
+    #[test]
+    fn test_collam_double_free() {
+        unsafe {
+            let collam = Collam::new();
+            collam.config().set_check_action(0);
+            let layout = util::pad_to_scalar(64).expect("unable to align layout");
+            let ptr = collam.alloc(layout);
+            let block = block_of(ptr);
+            assert!(collam.check_in_use(block, "free"));
+            collam.dealloc(ptr, layout);
+            assert_eq!(block.state(), Some(BlockState::Free));
+
+            // Freed blocks are rejected before touching the heap or the thread cache.
+            let before = stats::snapshot();
+            assert!(!collam.check_in_use(block, "free"));
+            collam.dealloc(ptr, layout);
+            assert!(collam.realloc(ptr, layout, 128).is_null());
+            let after = stats::snapshot();
+            assert!(after.double_frees >= before.double_frees + 3);
+            assert_eq!(after.failed_verifies, before.failed_verifies);
+
+            // Pointers not returned by the allocator are reported as invalid instead.
+            let mut region = [0u8; 64];
+            let ptr = region.as_mut_ptr().add(BLOCK_META_SIZE);
+            let before = stats::snapshot();
+            collam.dealloc(ptr, layout);
+            let after = stats::snapshot();
+            assert!(after.failed_verifies > before.failed_verifies);
+            assert_eq!(after.double_frees, before.double_frees);
+
+            // Mmapped blocks are in use until they are unmapped.
+            let layout = util::pad_to_scalar(MMAP_THRESHOLD_DEFAULT).expect("unable to align");
+            let ptr = collam.alloc(layout);
+            assert_eq!(block_of(ptr).state(), Some(BlockState::Mmapped));
+            collam.dealloc(ptr, layout);
+        }
+    }
 }
@@ -0,0 +1,128 @@
+use libc_print::libc_eprintln;
+
+use crate::alloc::block::{BlockPtr, BlockState, BLOCK_LINKS_SIZE};
+
+/// Byte the memory of quarantined blocks is filled with.
+pub const QUARANTINE_POISON: u8 = 0xFB;
+
+/// FIFO of freed heap blocks, which are withheld from reuse to detect writes after free.
+/// The blocks are linked through their `next` member.
+pub struct Quarantine {
+    head: Option<BlockPtr>,
+    tail: Option<BlockPtr>,
+    /// Sum of the sizes of all quarantined blocks.
+    bytes: usize,
+}
+
+unsafe impl Send for Quarantine {}
+
+impl Quarantine {
+    pub const fn new() -> Self {
+        Quarantine {
+            head: None,
+            tail: None,
+            bytes: 0,
+        }
+    }
+
+    /// Returns the sum of the sizes of all quarantined blocks.
+    #[inline]
+    #[allow(unused)]
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Poisons the given freed heap block and appends it to the quarantine.
+    pub fn push(&mut self, mut block: BlockPtr) {
+        debug_assert!(!block.is_mmapped() && !block.is_free() && !block.is_cached());
+        block.set_state(BlockState::Quarantined);
+        block.fill_unlinked(QUARANTINE_POISON);
+        block.as_mut().prev = None;
+        block.as_mut().next = None;
+        match self.tail {
+            Some(mut tail) => tail.as_mut().next = Some(block),
+            None => self.head = Some(block),
+        }
+        self.tail = Some(block);
+        self.bytes += block.size();
+        dprintln!("[quarantine]: added {} at {:p}", block.as_ref(), block);
+    }
+
+    /// Removes and returns the oldest block if the quarantine holds more than `max` bytes.
+    /// The block is marked in use again, so it can be released as usual.
+    pub fn evict(&mut self, max: usize) -> Option<BlockPtr> {
+        if self.bytes <= max {
+            return None;
+        }
+        let mut block = self.head?;
+        debug_assert_eq!(block.state(), Some(BlockState::Quarantined));
+        self.head = block.as_ref().next;
+        if self.head.is_none() {
+            self.tail = None;
+        }
+        self.bytes -= block.size();
+        block.as_mut().next = None;
+        block.set_state(BlockState::Allocated);
+        dprintln!("[quarantine]: evicted {} at {:p}", block.as_ref(), block);
+        Some(block)
+    }
+}
+
+/// Returns the offset of the first byte of the memory region of an evicted block,
+/// which has been modified since it has been poisoned, if any.
+/// NOTE: Writes to the first `BLOCK_LINKS_SIZE` bytes, which hold the link, are not detected.
+pub fn modified(block: BlockPtr) -> Option<usize> {
+    let region = unsafe {
+        core::slice::from_raw_parts(block.mem_region().cast::<u8>().as_ptr(), block.size())
+    };
+    region[BLOCK_LINKS_SIZE..]
+        .iter()
+        .position(|byte| *byte != QUARANTINE_POISON)
+        .map(|offset| BLOCK_LINKS_SIZE + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::ffi::c_void;
+    use core::ptr::Unique;
+
+    use crate::alloc::block::BLOCK_META_SIZE;
+
+    #[test]
+    fn test_quarantine() {
+        let block_size = 64;
+        let ptr = unsafe {
+            Unique::new(libc::malloc(3 * (BLOCK_META_SIZE + block_size)))
+                .expect("unable to allocate memory")
+        };
+        let blocks: std::vec::Vec<BlockPtr> = (0..3)
+            .map(|idx| unsafe {
+                let ptr = ptr.as_ptr().add(idx * (BLOCK_META_SIZE + block_size));
+                BlockPtr::new(Unique::new_unchecked(ptr.cast::<c_void>()), block_size)
+            })
+            .collect();
+
+        let mut quarantine = Quarantine::new();
+        for block in blocks.iter() {
+            quarantine.push(*block);
+            assert_eq!(block.state(), Some(BlockState::Quarantined));
+            assert_eq!(modified(*block), None);
+        }
+        assert_eq!(quarantine.bytes(), 3 * block_size);
+        assert_eq!(quarantine.evict(3 * block_size), None);
+
+        // Blocks leave in the order they have been added.
+        unsafe { *blocks[0].mem_region().cast::<u8>().as_ptr().add(40) = 0 };
+        let block = quarantine.evict(block_size).expect("no block evicted");
+        assert_eq!(block, blocks[0]);
+        assert_eq!(block.state(), Some(BlockState::Allocated));
+        assert_eq!(modified(block), Some(40));
+        assert_eq!(quarantine.evict(block_size), Some(blocks[1]));
+        assert_eq!(quarantine.evict(block_size), None);
+        assert_eq!(quarantine.evict(0), Some(blocks[2]));
+        assert_eq!(quarantine.evict(0), None);
+        assert_eq!(quarantine.bytes(), 0);
+        unsafe { libc::free(ptr.as_ptr()) };
+    }
+}
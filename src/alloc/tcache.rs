@@ -0,0 +1,307 @@
+use core::{ffi::c_void, mem, ptr};
+
+use libc_print::libc_eprintln;
+
+use crate::alloc::block::BlockPtr;
+use crate::alloc::Collam;
+
+/// Size difference between two neighbouring cache bins.
+const TCACHE_GRANULARITY: usize = mem::align_of::<libc::max_align_t>();
+/// Blocks larger than this are never cached.
+pub const TCACHE_MAX_SIZE: usize = 1024;
+/// Number of cache bins, each holding blocks of one exact size.
+const TCACHE_BINS: usize = TCACHE_MAX_SIZE / TCACHE_GRANULARITY + 1;
+/// Maximum number of blocks kept in a single cache bin.
+pub const TCACHE_BIN_LIMIT: usize = 16;
+/// Maximum sum of block sizes kept by a single thread.
+pub const TCACHE_MAX_BYTES: usize = 64 * 1024;
+
+#[thread_local]
+static mut TCACHE: TCache = TCache::new();
+
+/// Key used to flush the cache of a thread on exit, `None` if it could not be created.
+static TCACHE_KEY: spin::Once<Option<libc::pthread_key_t>> = spin::Once::new();
+
+/// Per-thread cache of small in use blocks, which serves allocations without locking.
+/// All cached blocks belong to a single `Collam` instance at a time.
+pub struct TCache {
+    /// Id of the owning `Collam` instance or 0 if the cache is unbound.
+    owner: usize,
+    /// The owning instance, the cache is flushed to it on thread exit.
+    collam: *const Collam,
+    bins: [Option<BlockPtr>; TCACHE_BINS],
+    counts: [usize; TCACHE_BINS],
+    /// Sum of the sizes of all cached blocks.
+    bytes: usize,
+    /// Set if the flush on thread exit has been registered.
+    registered: bool,
+}
+
+impl TCache {
+    const fn new() -> Self {
+        TCache {
+            owner: 0,
+            collam: ptr::null(),
+            bins: [None; TCACHE_BINS],
+            counts: [0; TCACHE_BINS],
+            bytes: 0,
+            registered: false,
+        }
+    }
+
+    /// Returns the cache of the current thread.
+    #[inline]
+    pub unsafe fn get() -> &'static mut TCache {
+        &mut *ptr::addr_of_mut!(TCACHE)
+    }
+
+    /// Removes and returns a cached block of the given size owned by `collam`.
+    pub fn pop(&mut self, collam: &Collam, size: usize) -> Option<BlockPtr> {
+        let idx = bin_index(size)?;
+        if self.owner != collam.id() {
+            return None;
+        }
+        let mut block = self.bins[idx]?;
+        self.bins[idx] = block.as_ref().next;
+        self.counts[idx] -= 1;
+        self.bytes -= block.size();
+        block.as_mut().unlink();
+        block.set_cached(false);
+        dprintln!("[tcache]: took {} at {:p}", block.as_ref(), block);
+        Some(block)
+    }
+
+    /// Adds the given in use heap block owned by `collam` to the cache.
+    /// Returns `false` if the block cannot be cached and has to be released.
+    pub fn push(&mut self, collam: &Collam, mut block: BlockPtr) -> bool {
+        debug_assert!(!block.is_mmapped() && !block.is_free());
+        let idx = match bin_index(block.size()) {
+            Some(idx) => idx,
+            None => return false,
+        };
+        // Cached blocks are left to the heap to detect double frees.
+        if block.is_cached()
+            || self.counts[idx] >= TCACHE_BIN_LIMIT
+            || self.bytes + block.size() > TCACHE_MAX_BYTES
+        {
+            return false;
+        }
+        if self.owner != collam.id() {
+            // Only an empty cache may change its owner.
+            if self.bytes != 0 {
+                return false;
+            }
+            self.owner = collam.id();
+            self.collam = collam;
+        }
+        if !self.registered && !unsafe { register() } {
+            return false;
+        }
+        self.registered = true;
+
+        block.set_cached(true);
+        block.as_mut().prev = None;
+        block.as_mut().next = self.bins[idx];
+        self.bins[idx] = Some(block);
+        self.counts[idx] += 1;
+        self.bytes += block.size();
+        dprintln!("[tcache]: cached {} at {:p}", block.as_ref(), block);
+        true
+    }
+
+    /// Returns `true` if the cached blocks belong to `collam`.
+    #[inline]
+    pub fn owns(&self, collam: &Collam) -> bool {
+        // Avoid assigning an id to instances which have never been used.
+        ptr::eq(self.collam, collam) && self.owner == collam.id()
+    }
+
+    /// Returns the sum of the sizes of all cached blocks.
+    #[inline]
+    #[allow(unused)]
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Releases all cached blocks to the owning `Collam` instance and unbinds the cache.
+    pub unsafe fn flush(&mut self) {
+        if self.collam.is_null() {
+            return;
+        }
+        let collam = &*self.collam;
+        for idx in 0..TCACHE_BINS {
+            while let Some(mut block) = self.bins[idx] {
+                self.bins[idx] = block.as_ref().next;
+                block.as_mut().unlink();
+                block.set_cached(false);
+                collam.release_block(block);
+            }
+            self.counts[idx] = 0;
+        }
+        dprintln!("[tcache]: flushed {} bytes", self.bytes);
+        self.bytes = 0;
+        self.owner = 0;
+        self.collam = ptr::null();
+    }
+}
+
+/// Returns the cache bin index for blocks of the given size,
+/// or `None` if blocks of this size are not cached.
+#[inline]
+fn bin_index(size: usize) -> Option<usize> {
+    if size > TCACHE_MAX_SIZE {
+        return None;
+    }
+    Some(size / TCACHE_GRANULARITY)
+}
+
+/// Registers `destroy` to be called on exit of the current thread.
+/// Returns `false` if the key could not be created or set.
+unsafe fn register() -> bool {
+    let key = TCACHE_KEY.call_once(|| {
+        let mut key: libc::pthread_key_t = 0;
+        match libc::pthread_key_create(&mut key, Some(destroy)) {
+            0 => Some(key),
+            _ => None,
+        }
+    });
+    match key {
+        Some(key) => {
+            let cache = ptr::addr_of_mut!(TCACHE).cast::<c_void>();
+            libc::pthread_setspecific(*key, cache) == 0
+        }
+        None => false,
+    }
+}
+
+/// Flushes the cache of an exiting thread.
+unsafe extern "C" fn destroy(cache: *mut c_void) {
+    let cache = &mut *cache.cast::<TCache>();
+    // Blocks freed by later destructors register the flush again.
+    cache.registered = false;
+    cache.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::alloc::{GlobalAlloc, Layout};
+
+    use crate::alloc::block::{BlockState, BLOCK_MIN_REGION_SIZE};
+
+    fn block_of(ptr: *mut u8) -> BlockPtr {
+        let ptr = ptr::Unique::new(ptr.cast::<c_void>()).expect("got null pointer");
+        BlockPtr::from_mem_region(ptr).expect("unable to get block")
+    }
+
+    #[test]
+    fn test_bin_index() {
+        assert_eq!(bin_index(BLOCK_MIN_REGION_SIZE), Some(2));
+        assert_eq!(bin_index(TCACHE_MAX_SIZE), Some(TCACHE_BINS - 1));
+        assert_eq!(bin_index(TCACHE_MAX_SIZE + 16), None);
+    }
+
+    #[test]
+    fn test_tcache_reuse() {
+        let collam = Collam::new();
+        unsafe {
+            let layout = Layout::from_size_align_unchecked(64, 16);
+            let ptr = collam.alloc(layout);
+            assert!(!ptr.is_null());
+            collam.dealloc(ptr, layout);
+            let block = block_of(ptr);
+            assert!(block.is_cached() && !block.is_free());
+            assert_eq!(block.state(), Some(BlockState::Free));
+            assert!(TCache::get().bytes() >= 64);
+
+            // The cached block is served again without touching the heap.
+            let ptr2 = collam.alloc(layout);
+            assert_eq!(ptr2, ptr);
+            assert!(!block.is_cached());
+            assert_eq!(block.state(), Some(BlockState::Allocated));
+            collam.dealloc(ptr2, layout);
+        }
+    }
+
+    #[test]
+    fn test_tcache_bin_limit() {
+        let collam = Collam::new();
+        unsafe {
+            let layout = Layout::from_size_align_unchecked(128, 16);
+            let ptrs: std::vec::Vec<_> = (0..TCACHE_BIN_LIMIT + 4)
+                .map(|_| collam.alloc(layout))
+                .collect();
+            for ptr in ptrs.iter() {
+                collam.dealloc(*ptr, layout);
+            }
+            let cached = ptrs.iter().filter(|p| block_of(**p).is_cached()).count();
+            assert_eq!(cached, TCACHE_BIN_LIMIT);
+            assert_eq!(
+                TCache::get().counts[bin_index(128).unwrap()],
+                TCACHE_BIN_LIMIT
+            );
+        }
+    }
+
+    #[test]
+    fn test_tcache_large_blocks_bypass() {
+        let collam = Collam::new();
+        unsafe {
+            let layout = Layout::from_size_align_unchecked(TCACHE_MAX_SIZE + 16, 16);
+            let ptr = collam.alloc(layout);
+            collam.dealloc(ptr, layout);
+            assert!(!block_of(ptr).is_cached());
+        }
+    }
+
+    #[test]
+    fn test_tcache_flush_on_drop() {
+        let ptr;
+        {
+            let collam = Collam::new();
+            let layout = unsafe { Layout::from_size_align_unchecked(32, 16) };
+            ptr = unsafe { collam.alloc(layout) };
+            unsafe { collam.dealloc(ptr, layout) };
+            assert!(block_of(ptr).is_cached());
+        }
+        let cache = unsafe { TCache::get() };
+        assert_eq!(cache.bytes(), 0);
+        assert_eq!(cache.owner, 0);
+        assert!(!block_of(ptr).is_cached());
+    }
+
+    #[test]
+    fn test_tcache_foreign_owner() {
+        let collam = Collam::new();
+        let collam2 = Collam::new();
+        unsafe {
+            let layout = Layout::from_size_align_unchecked(48, 16);
+            let ptr = collam.alloc(layout);
+            let ptr2 = collam2.alloc(layout);
+            collam.dealloc(ptr, layout);
+            // The cache is bound to the first instance.
+            collam2.dealloc(ptr2, layout);
+            assert!(block_of(ptr).is_cached());
+            assert!(!block_of(ptr2).is_cached());
+            assert_ne!(collam2.alloc(layout), ptr);
+        }
+    }
+
+    #[test]
+    fn test_tcache_flush_on_thread_exit() {
+        static COLLAM: Collam = Collam::new();
+        let ptr = std::thread::spawn(|| unsafe {
+            let layout = Layout::from_size_align_unchecked(96, 16);
+            let ptr = COLLAM.alloc(layout);
+            // Keeps the heap from being trimmed after the flush.
+            COLLAM.alloc(layout);
+            COLLAM.dealloc(ptr, layout);
+            assert!(block_of(ptr).is_cached());
+            ptr as usize
+        })
+        .join()
+        .expect("thread panicked");
+        let block = block_of(ptr as *mut u8);
+        assert!(!block.is_cached() && block.is_free());
+    }
+}
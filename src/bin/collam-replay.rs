@@ -0,0 +1,334 @@
+//! Replays an allocation trace recorded with `trace_path` against `Collam`
+//! or the system allocator and reports timings, peak RSS and fragmentation.
+//!
+//! Usage: collam-replay <trace> [--allocator collam|system|both]
+//!
+//! Calls are replayed by a single thread in the order of their timestamps.
+//! With `both`, each allocator replays the trace in its own process,
+//! so the peak RSS values do not influence each other.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::process::{self, Command};
+use std::time::Instant;
+use std::{env, fs, mem};
+
+use collam::trace::{Op, Record, HEADER_SIZE, RECORD_SIZE, TRACE_MAGIC, TRACE_VERSION};
+use collam::Collam;
+
+static COLLAM: Collam = Collam::new();
+
+/// Alignment of blocks returned by `malloc`.
+const DEFAULT_ALIGN: usize = mem::align_of::<libc::max_align_t>();
+
+/// Replayed operations, each with its latencies in nanoseconds.
+const OPS: [Op; 5] = [Op::Malloc, Op::Calloc, Op::Realloc, Op::Free, Op::Memalign];
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let (path, allocator) = match args.as_slice() {
+        [_, path] => (path, "both"),
+        [_, path, flag, allocator] if flag == "--allocator" => (path, allocator.as_str()),
+        _ => usage(),
+    };
+
+    match allocator {
+        "collam" | "system" => {
+            let records = match read_trace(path) {
+                Ok(records) => records,
+                Err(err) => {
+                    eprintln!("collam-replay: {}: {}", path, err);
+                    process::exit(1);
+                }
+            };
+            if allocator == "collam" {
+                report("collam", &replay(&COLLAM, &records, collam_heap));
+            } else {
+                report("system", &replay(&System, &records, system_heap));
+            }
+        }
+        "both" => {
+            for allocator in ["collam", "system"].iter() {
+                let status = Command::new(&args[0])
+                    .args([path.as_str(), "--allocator", allocator])
+                    .status();
+                if !status.is_ok_and(|status| status.success()) {
+                    process::exit(1);
+                }
+                println!();
+            }
+        }
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: collam-replay <trace> [--allocator collam|system|both]");
+    process::exit(2);
+}
+
+/// Reads the records of the trace file at `path`, ordered by their timestamps.
+fn read_trace(path: &str) -> Result<Vec<Record>, String> {
+    let data = fs::read(path).map_err(|err| err.to_string())?;
+    parse_trace(&data)
+}
+
+/// Parses the records of a trace, ordered by their timestamps.
+fn parse_trace(data: &[u8]) -> Result<Vec<Record>, String> {
+    if data.len() < HEADER_SIZE || data[..TRACE_MAGIC.len()] != TRACE_MAGIC {
+        return Err("not a collam trace".into());
+    }
+    let version = u32::from_ne_bytes([data[8], data[9], data[10], data[11]]);
+    let record_size = u32::from_ne_bytes([data[12], data[13], data[14], data[15]]);
+    if version != TRACE_VERSION || record_size as usize != RECORD_SIZE {
+        return Err(format!("unsupported trace version {}", version));
+    }
+    let mut records: Vec<Record> = data[HEADER_SIZE..]
+        .chunks_exact(RECORD_SIZE)
+        .filter_map(Record::from_bytes)
+        .collect();
+    // Records of different threads are interleaved.
+    records.sort_by_key(|record| record.time);
+    Ok(records)
+}
+
+/// Outcome of replaying a trace.
+#[derive(Default)]
+struct Replay {
+    /// Latencies in nanoseconds by index of the operation in `OPS`.
+    latencies: [Vec<u64>; 5],
+    total_ns: u64,
+    /// Calls skipped because their pointer was not allocated within the trace.
+    skipped: usize,
+    /// Calls which failed during the replay, but not in the trace.
+    failed: usize,
+    /// Maximum resident set size of the process in KiB.
+    peak_rss_kb: i64,
+    /// Free bytes of the heap at the end of the trace.
+    free: usize,
+    /// Fragmentation ratio at the end of the trace, if known.
+    fragmentation: Option<f64>,
+}
+
+/// Returns the free bytes and the fragmentation ratio of the `Collam` heap.
+fn collam_heap() -> (usize, Option<f64>) {
+    let report = COLLAM.fragmentation();
+    (report.free, Some(report.ratio()))
+}
+
+/// Returns the free bytes of the system heap, glibc does not expose its fragmentation.
+fn system_heap() -> (usize, Option<f64>) {
+    let info = unsafe { libc::mallinfo2() };
+    (info.fordblks, None)
+}
+
+/// Replays the records against `alloc`, inspects its heap with `heap` at the end of the trace
+/// and frees all remaining blocks afterwards.
+fn replay<A: GlobalAlloc>(
+    alloc: &A,
+    records: &[Record],
+    heap: fn() -> (usize, Option<f64>),
+) -> Replay {
+    let mut result = Replay::default();
+    // Recorded pointers mapped to the live blocks of the replay.
+    let mut live: HashMap<u64, (*mut u8, Layout)> = HashMap::with_capacity(records.len() / 2);
+
+    for record in records {
+        let op = match Op::from_u8(record.op) {
+            Some(op) => op,
+            None => continue,
+        };
+        let size = record.size as usize;
+        let align = match record.align as usize {
+            0 => DEFAULT_ALIGN,
+            align => align.max(DEFAULT_ALIGN),
+        };
+        let old = match op {
+            Op::Realloc | Op::Free if record.ptr != 0 => match live.remove(&record.ptr) {
+                Some(old) => Some(old),
+                None => {
+                    result.skipped += 1;
+                    continue;
+                }
+            },
+            _ => None,
+        };
+        // Failed calls of the trace are not replayed, they did not change the heap.
+        if record.result == 0 && op != Op::Free && !(op == Op::Realloc && size == 0) {
+            if let Some((ptr, layout)) = old {
+                live.insert(record.ptr, (ptr, layout));
+            }
+            continue;
+        }
+        let layout = match Layout::from_size_align(size.max(1), align) {
+            Ok(layout) => layout,
+            Err(_) => {
+                result.skipped += 1;
+                continue;
+            }
+        };
+
+        let start = Instant::now();
+        let ptr = unsafe {
+            match (op, old) {
+                (Op::Free, Some((ptr, layout))) => {
+                    alloc.dealloc(ptr, layout);
+                    None
+                }
+                (Op::Free, None) => None,
+                (Op::Realloc, Some((ptr, layout))) if size == 0 => {
+                    alloc.dealloc(ptr, layout);
+                    None
+                }
+                (Op::Realloc, Some((ptr, old_layout))) => {
+                    Some(alloc.realloc(ptr, old_layout, layout.size()))
+                }
+                (Op::Calloc, _) => Some(alloc.alloc_zeroed(layout)),
+                _ => Some(alloc.alloc(layout)),
+            }
+        };
+        let elapsed = start.elapsed().as_nanos() as u64;
+        result.total_ns += elapsed;
+        result.latencies[op as usize - 1].push(elapsed);
+
+        match ptr {
+            Some(ptr) if ptr.is_null() => result.failed += 1,
+            Some(ptr) => {
+                // Blocks are touched like the traced program presumably did.
+                unsafe { ptr.write_bytes(0xA5, layout.size().min(4096)) };
+                if let Some((old, old_layout)) = live.insert(record.result, (ptr, layout)) {
+                    // The trace missed a free, e.g. of a block freed before tracing started.
+                    unsafe { alloc.dealloc(old, old_layout) };
+                }
+            }
+            None => (),
+        }
+    }
+
+    result.peak_rss_kb = peak_rss_kb();
+    let (free, fragmentation) = heap();
+    result.free = free;
+    result.fragmentation = fragmentation;
+    for (_, (ptr, layout)) in live.drain() {
+        unsafe { alloc.dealloc(ptr, layout) };
+    }
+    result
+}
+
+/// Returns the maximum resident set size of the process in KiB.
+fn peak_rss_kb() -> i64 {
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    usage.ru_maxrss
+}
+
+/// Returns the value at the given percentile of the sorted `values`.
+fn percentile(sorted: &[u64], percent: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (percent / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank.min(sorted.len() - 1)]
+}
+
+fn report(name: &str, replay: &Replay) {
+    let calls: usize = replay.latencies.iter().map(Vec::len).sum();
+    println!("allocator:      {}", name);
+    println!("calls:          {}", calls);
+    println!("total time:     {:.3} ms", replay.total_ns as f64 / 1e6);
+    println!("skipped calls:  {}", replay.skipped);
+    println!("failed calls:   {}", replay.failed);
+    println!("peak RSS:       {} KiB", replay.peak_rss_kb);
+    println!("free bytes:     {}", replay.free);
+    match replay.fragmentation {
+        Some(ratio) => println!("fragmentation:  {:.4}", ratio),
+        None => println!("fragmentation:  n/a"),
+    }
+    println!("latency (ns)    count        p50        p90        p99      p99.9        max");
+    for (op, latencies) in OPS.iter().zip(replay.latencies.iter()) {
+        if latencies.is_empty() {
+            continue;
+        }
+        let mut sorted = latencies.clone();
+        sorted.sort_unstable();
+        println!(
+            "{:<10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            format!("{:?}", op).to_lowercase(),
+            sorted.len(),
+            percentile(&sorted, 50.0),
+            percentile(&sorted, 90.0),
+            percentile(&sorted, 99.0),
+            percentile(&sorted, 99.9),
+            sorted[sorted.len() - 1]
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(op: Op, time: u64, ptr: u64, size: u64, result: u64) -> Record {
+        let mut record = Record::default();
+        record.op = op as u8;
+        record.time = time;
+        record.ptr = ptr;
+        record.size = size;
+        record.result = result;
+        record
+    }
+
+    fn trace(records: &[Record]) -> Vec<u8> {
+        let mut data = TRACE_MAGIC.to_vec();
+        data.extend_from_slice(&TRACE_VERSION.to_ne_bytes());
+        data.extend_from_slice(&(RECORD_SIZE as u32).to_ne_bytes());
+        for record in records {
+            data.extend_from_slice(record.as_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_trace() {
+        let data = trace(&[
+            record(Op::Free, 20, 0x10, 0, 0),
+            record(Op::Malloc, 10, 0, 64, 0x10),
+        ]);
+        let records = parse_trace(&data).expect("unable to parse trace");
+        assert_eq!(records.len(), 2);
+        assert_eq!(Op::from_u8(records[0].op), Some(Op::Malloc));
+        assert!(parse_trace(b"CLMTRAC").is_err());
+        assert!(parse_trace(&data[1..]).is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut aligned = record(Op::Memalign, 7, 0, 100, 0x50);
+        aligned.align = 4096;
+        let records = [
+            record(Op::Malloc, 1, 0, 64, 0x10),
+            record(Op::Calloc, 2, 0, 128, 0x20),
+            record(Op::Realloc, 3, 0x10, 4096, 0x30),
+            record(Op::Free, 4, 0x20, 0, 0),
+            // Allocated before tracing started.
+            record(Op::Free, 5, 0x40, 0, 0),
+            // Failed in the trace.
+            record(Op::Malloc, 6, 0, 1 << 40, 0),
+            aligned,
+        ];
+        let result = replay(&System, &records, system_heap);
+        let calls: usize = result.latencies.iter().map(Vec::len).sum();
+        assert_eq!(calls, 5);
+        assert_eq!((result.skipped, result.failed), (1, 0));
+        assert!(result.peak_rss_kb > 0);
+    }
+
+    #[test]
+    fn test_percentile() {
+        let values: Vec<u64> = (1..=100).collect();
+        assert_eq!(percentile(&values, 50.0), 51);
+        assert_eq!(percentile(&values, 99.0), 99);
+        assert_eq!(percentile(&values, 100.0), 100);
+        assert_eq!(percentile(&[], 50.0), 0);
+    }
+}
@@ -0,0 +1,248 @@
+//! Opt-in tracking of the live allocations of the C ABI, which are reported at exit.
+//! Allocations are kept in a hash table mapped directly from the OS,
+//! so tracking never allocates itself.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::{cmp, ffi::c_void, fmt, mem};
+
+use crate::table::{Entry, Table};
+use crate::util::FdWriter;
+
+/// Number of the largest outstanding allocations included in a report.
+pub const REPORT_LARGEST: usize = 10;
+
+static MODE: AtomicU8 = AtomicU8::new(Mode::Off as u8);
+static TABLE: spin::Mutex<Table<Allocation>> = spin::Mutex::new(Table::new());
+/// Set once the report at exit has been registered successfully.
+static REPORT_AT_EXIT: spin::Once<bool> = spin::Once::new();
+
+/// Which allocations are tracked.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Off = 0,
+    /// Tracks the address and size of each allocation.
+    On = 1,
+    /// Additionally tracks the return address of the allocating call.
+    Callers = 2,
+}
+
+/// A live allocation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Allocation {
+    /// Address of the allocation, 0 for empty slots of the table.
+    pub ptr: usize,
+    /// Requested size.
+    pub size: usize,
+    /// Return address of the allocating call, 0 if not captured.
+    pub caller: usize,
+}
+
+/// Outstanding allocations at the time of a report.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Summary {
+    /// Number of outstanding allocations.
+    pub count: usize,
+    /// Total requested bytes of all outstanding allocations.
+    pub bytes: usize,
+    /// Largest outstanding allocations in descending order of their size,
+    /// the first `min(count, REPORT_LARGEST)` are valid.
+    pub largest: [Allocation; REPORT_LARGEST],
+}
+
+impl Entry for Allocation {
+    #[inline]
+    fn key(&self) -> usize {
+        self.ptr
+    }
+}
+
+impl Summary {
+    /// Returns the valid entries of `largest`.
+    pub fn largest(&self) -> &[Allocation] {
+        &self.largest[..cmp::min(self.count, REPORT_LARGEST)]
+    }
+
+    /// Counts the allocation and keeps it if it is among the largest.
+    fn add(&mut self, allocation: Allocation) {
+        let len = cmp::min(self.count, REPORT_LARGEST);
+        self.count += 1;
+        self.bytes += allocation.size;
+        let idx = self.largest[..len]
+            .iter()
+            .position(|other| other.size < allocation.size)
+            .unwrap_or(len);
+        if idx < REPORT_LARGEST {
+            let end = cmp::min(len, REPORT_LARGEST - 1);
+            self.largest.copy_within(idx..end, idx + 1);
+            self.largest[idx] = allocation;
+        }
+    }
+
+    /// Writes the summary as human readable text. Return addresses are resolved
+    /// to the object containing them and the offset within it, if possible.
+    pub fn write(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(
+            out,
+            "{} leaked allocations, {} bytes",
+            self.count, self.bytes
+        )?;
+        for allocation in self.largest() {
+            write!(
+                out,
+                "{:>12} bytes at {:#x}",
+                allocation.size, allocation.ptr
+            )?;
+            if allocation.caller != 0 {
+                write!(out, " from {:#x}", allocation.caller)?;
+                write_object(out, allocation.caller)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the object containing `addr` and the offset within it, if known.
+pub(crate) fn write_object(out: &mut dyn fmt::Write, addr: usize) -> fmt::Result {
+    let mut info: libc::Dl_info = unsafe { mem::zeroed() };
+    if unsafe { libc::dladdr(addr as *const c_void, &mut info) } == 0 || info.dli_fname.is_null() {
+        return Ok(());
+    }
+    let name = unsafe { core::ffi::CStr::from_ptr(info.dli_fname) };
+    write!(
+        out,
+        " ({}+{:#x})",
+        name.to_str().unwrap_or("?"),
+        addr - info.dli_fbase as usize
+    )
+}
+
+/// Returns which allocations are tracked.
+#[inline]
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => Mode::On,
+        2 => Mode::Callers,
+        _ => Mode::Off,
+    }
+}
+
+/// Returns `true` if allocations are tracked.
+#[inline]
+pub fn enabled() -> bool {
+    MODE.load(Ordering::Relaxed) != Mode::Off as u8
+}
+
+/// Sets which allocations are tracked and registers the report at exit once enabled.
+/// Disabling forgets all tracked allocations.
+/// Returns `false` if the report at exit could not be registered.
+/// NOTE: Allocations made before tracking has been enabled are not reported.
+pub fn set_mode(mode: Mode) -> bool {
+    MODE.store(mode as u8, Ordering::Relaxed);
+    if mode == Mode::Off {
+        TABLE.lock().clear();
+        return true;
+    }
+    *REPORT_AT_EXIT.call_once(|| unsafe { libc::atexit(report_at_exit) == 0 })
+}
+
+/// Tracks a new allocation of `size` bytes at `ptr` made from `caller`, if enabled.
+#[inline]
+pub fn track(ptr: *mut c_void, size: usize, caller: *const ()) {
+    let mode = mode();
+    if mode == Mode::Off || ptr.is_null() {
+        return;
+    }
+    let allocation = Allocation {
+        ptr: ptr as usize,
+        size,
+        caller: match mode {
+            Mode::Callers => caller as usize,
+            _ => 0,
+        },
+    };
+    // Allocations not fitting into the table are not reported.
+    TABLE.lock().insert(allocation);
+}
+
+/// Stops tracking the allocation at `ptr`, if enabled, and returns it.
+/// NOTE: Must be called before the memory is released, as it may be reused right away.
+#[inline]
+pub fn untrack(ptr: *mut c_void) -> Option<Allocation> {
+    if enabled() && !ptr.is_null() {
+        TABLE.lock().remove(ptr as usize)
+    } else {
+        None
+    }
+}
+
+/// Returns a summary of the outstanding tracked allocations.
+pub fn summary() -> Summary {
+    let mut summary = Summary::default();
+    let table = TABLE.lock();
+    for allocation in table.iter() {
+        summary.add(*allocation);
+    }
+    summary
+}
+
+extern "C" fn report_at_exit() {
+    if enabled() {
+        let mut out = FdWriter(libc::STDERR_FILENO);
+        let _ = out.write_str("[libcollam.so]: ");
+        let _ = summary().write(&mut out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocation(ptr: usize, size: usize) -> Allocation {
+        Allocation {
+            ptr,
+            size,
+            caller: 0,
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut summary = Summary::default();
+        for size in [5, 30, 10, 20, 1, 40, 15, 25, 35, 45, 50, 2].iter() {
+            summary.add(allocation(*size * 16, *size));
+        }
+        assert_eq!((summary.count, summary.bytes), (12, 278));
+        let sizes: std::vec::Vec<usize> = summary.largest().iter().map(|a| a.size).collect();
+        assert_eq!(sizes, [50, 45, 40, 35, 30, 25, 20, 15, 10, 5]);
+
+        let mut out = std::string::String::new();
+        summary.write(&mut out).unwrap();
+        assert!(out.starts_with("12 leaked allocations, 278 bytes\n"));
+        assert!(out.contains("          50 bytes at 0x320\n"));
+    }
+
+    #[test]
+    fn test_track() {
+        let caller = test_track as *const ();
+        assert!(set_mode(Mode::Callers));
+        track(0x1000 as *mut c_void, 64, caller);
+        track(0x2000 as *mut c_void, 128, caller);
+        track(core::ptr::null_mut(), 256, caller);
+        untrack(0x1000 as *mut c_void);
+        let report = summary();
+        assert!(set_mode(Mode::Off));
+
+        assert_eq!((report.count, report.bytes), (1, 128));
+        assert_eq!(report.largest()[0].caller, caller as usize);
+        let mut out = std::string::String::new();
+        report.write(&mut out).unwrap();
+        // The test binary contains the caller.
+        assert!(out.contains("+0x"), "{}", out);
+
+        track(0x3000 as *mut c_void, 64, caller);
+        assert_eq!(summary().count, 0);
+    }
+}
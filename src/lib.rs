@@ -1,8 +1,8 @@
-#![feature(stmt_expr_attributes)]
-#![feature(lang_items)]
+#![cfg_attr(not(any(test, feature = "std")), feature(lang_items))]
 #![feature(core_intrinsics)]
 #![feature(ptr_internals)]
-#![feature(alloc_layout_extra)]
+#![feature(thread_local)]
+#![allow(internal_features)]
 #![no_std]
 
 extern crate libc;
@@ -14,30 +14,36 @@ extern crate spin;
 #[cfg(test)]
 #[macro_use]
 extern crate std;
+// Linking std provides the panic runtime, so the lang items below must not be defined.
+#[cfg(all(feature = "std", not(test)))]
+extern crate std;
 
+#[cfg(not(any(test, feature = "std")))]
 use core::{intrinsics, panic};
 
+#[cfg(not(any(test, feature = "std")))]
 use libc_print::libc_eprintln;
 
 mod macros;
 pub mod alloc;
-#[cfg(all(feature = "posix", not(test)))]
+pub mod leaks;
+#[cfg(feature = "posix")]
 pub mod posix;
-#[cfg(feature = "stats")]
-mod stats;
+pub mod profile;
+pub mod stats;
+mod table;
+pub mod trace;
 mod util;
 
-#[cfg(not(test))]
+pub use crate::alloc::Collam;
+
+#[cfg(not(any(test, feature = "std")))]
 #[panic_handler]
 fn panic(info: &panic::PanicInfo) -> ! {
     eprintln!("[libcollam.so]: panic occurred: {:?}", info);
-    unsafe { intrinsics::abort() };
+    intrinsics::abort();
 }
 
-#[cfg(not(test))]
+#[cfg(not(any(test, feature = "std")))]
 #[lang = "eh_personality"]
 extern "C" fn eh_personality() {}
-
-#[cfg(not(test))]
-#[lang = "eh_unwind_resume"]
-extern "C" fn eh_unwind_resume() {}
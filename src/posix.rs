@@ -1,20 +1,35 @@
+//! C ABI exports. Safety requirements follow the corresponding POSIX/glibc man pages.
+#![allow(clippy::missing_safety_doc)]
+
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::{null_mut, Unique};
-use core::{ffi::c_void, intrinsics::unlikely, mem};
+use core::{cmp, ffi::c_void, fmt, intrinsics, intrinsics::unlikely, mem};
 
 use libc_print::libc_eprintln;
 
-use crate::alloc::{block::BlockPtr, Collam};
+use crate::alloc::config::{
+    ErrorHandler, CHECK_ACTION_ABORT, CHECK_ACTION_BACKTRACE, CHECK_ACTION_PRINT,
+};
+use crate::alloc::{block::BlockPtr, Collam, ARENA_LIMIT, PAGE_SIZE};
+use crate::trace::{self, Op};
+use crate::{leaks, profile, stats, util};
 
-static mut COLLAM: Collam = Collam::new();
+// The symbols are only exported outside of tests, so the test harness keeps the allocator
+// of libc while the functions below are called directly.
+static COLLAM: Collam = Collam::new();
+/// Set once the quarantine flush at exit has been registered successfully.
+static QUARANTINE_AT_EXIT: spin::Once<bool> = spin::Once::new();
 
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 pub unsafe extern "C" fn malloc(size: usize) -> *mut c_void {
     let layout = Layout::from_size_align_unchecked(size, mem::align_of::<libc::max_align_t>());
-    COLLAM.alloc(layout).cast::<c_void>()
+    let ptr = COLLAM.alloc(layout).cast::<c_void>();
+    trace::record(Op::Malloc, 0, null_mut(), size, 0, ptr);
+    leaks::track(ptr, size, intrinsics::return_address());
+    ptr
 }
 
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 pub unsafe extern "C" fn calloc(nobj: usize, size: usize) -> *mut c_void {
     let total_size = match nobj.checked_mul(size) {
         Some(x) => x,
@@ -28,11 +43,28 @@ pub unsafe extern "C" fn calloc(nobj: usize, size: usize) -> *mut c_void {
     };
     let layout =
         Layout::from_size_align_unchecked(total_size, mem::align_of::<libc::max_align_t>());
-    COLLAM.alloc_zeroed(layout).cast::<c_void>()
+    let ptr = COLLAM.alloc_zeroed(layout).cast::<c_void>();
+    trace::record(Op::Calloc, 0, null_mut(), total_size, 0, ptr);
+    leaks::track(ptr, total_size, intrinsics::return_address());
+    ptr
 }
 
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 pub unsafe extern "C" fn realloc(p: *mut c_void, size: usize) -> *mut c_void {
+    // The old block may be reused by other threads right away.
+    let time = trace::start();
+    let old = leaks::untrack(p);
+    let ptr = realloc_untraced(p, size);
+    trace::record(Op::Realloc, time, p, size, 0, ptr);
+    match old {
+        // The old block is left untouched if reallocation fails.
+        Some(old) if ptr.is_null() && size != 0 => leaks::track(p, old.size, old.caller as _),
+        _ => leaks::track(ptr, size, intrinsics::return_address()),
+    }
+    ptr
+}
+
+unsafe fn realloc_untraced(p: *mut c_void, size: usize) -> *mut c_void {
     if p.is_null() {
         // If ptr is NULL, then the call is equivalent to malloc(size), for all values of size.
         let layout = Layout::from_size_align_unchecked(size, mem::align_of::<libc::max_align_t>());
@@ -46,19 +78,133 @@ pub unsafe extern "C" fn realloc(p: *mut c_void, size: usize) -> *mut c_void {
         // If size is equal to zero, and ptr is not NULL,
         // then the call is equivalent to free(ptr).
         COLLAM.dealloc(p, layout);
+        flush_quarantine_at_exit();
         null_mut()
     } else {
-        COLLAM.realloc(p, layout, size).cast::<c_void>()
+        let ptr = COLLAM.realloc(p, layout, size).cast::<c_void>();
+        flush_quarantine_at_exit();
+        ptr
+    }
+}
+
+/// Registers the check of the blocks remaining in the quarantine at exit, once blocks
+/// may have been quarantined. Not done on allocation, since `atexit` may allocate itself.
+#[inline]
+fn flush_quarantine_at_exit() {
+    if unlikely(COLLAM.config().quarantine() != 0) {
+        QUARANTINE_AT_EXIT.call_once(|| unsafe { libc::atexit(quarantine_flush_at_exit) == 0 });
     }
 }
 
-#[no_mangle]
+extern "C" fn quarantine_flush_at_exit() {
+    COLLAM.flush_quarantine();
+}
+
+#[cfg_attr(not(test), no_mangle)]
 pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    // The block may be reused by other threads right away.
+    let time = trace::start();
+    leaks::untrack(ptr);
     let layout = Layout::from_size_align_unchecked(0, mem::align_of::<libc::max_align_t>());
-    COLLAM.dealloc(ptr.cast::<u8>(), layout)
+    COLLAM.dealloc(ptr.cast::<u8>(), layout);
+    flush_quarantine_at_exit();
+    trace::record(Op::Free, time, ptr, 0, 0, null_mut());
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn posix_memalign(
+    memptr: *mut *mut c_void,
+    alignment: usize,
+    size: usize,
+) -> i32 {
+    // The alignment must be a power of two multiple of sizeof(void *).
+    if !alignment.is_multiple_of(mem::size_of::<*mut c_void>()) {
+        return libc::EINVAL;
+    }
+    match alloc_aligned(alignment, size, intrinsics::return_address()) {
+        Ok(ptr) => {
+            *memptr = ptr;
+            0
+        }
+        Err(errno) => errno,
+    }
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn aligned_alloc(alignment: usize, size: usize) -> *mut c_void {
+    memalign_from(alignment, size, intrinsics::return_address())
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn memalign(alignment: usize, size: usize) -> *mut c_void {
+    memalign_from(alignment, size, intrinsics::return_address())
+}
+
+/// `memalign` called from `caller`.
+unsafe fn memalign_from(alignment: usize, size: usize, caller: *const ()) -> *mut c_void {
+    match alloc_aligned(alignment, size, caller) {
+        Ok(ptr) => ptr,
+        Err(errno) => {
+            set_errno(errno);
+            null_mut()
+        }
+    }
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn valloc(size: usize) -> *mut c_void {
+    memalign_from(*PAGE_SIZE, size, intrinsics::return_address())
 }
 
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn pvalloc(size: usize) -> *mut c_void {
+    // Rounds up the size to the next multiple of the page size.
+    let size = match util::pad_to_align(cmp::max(size, 1), *PAGE_SIZE) {
+        Ok(l) => l.size(),
+        Err(_) => {
+            set_errno(libc::ENOMEM);
+            return null_mut();
+        }
+    };
+    memalign_from(*PAGE_SIZE, size, intrinsics::return_address())
+}
+
+/// Allocates `size` bytes aligned to `alignment` on behalf of `caller`.
+/// Returns the allocated pointer or the errno value describing the error.
+unsafe fn alloc_aligned(
+    alignment: usize,
+    size: usize,
+    caller: *const (),
+) -> Result<*mut c_void, i32> {
+    let result = alloc_aligned_untraced(alignment, size);
+    let ptr = result.unwrap_or(null_mut());
+    trace::record(Op::Memalign, 0, null_mut(), size, alignment, ptr);
+    leaks::track(ptr, size, caller);
+    result
+}
+
+unsafe fn alloc_aligned_untraced(alignment: usize, size: usize) -> Result<*mut c_void, i32> {
+    if unlikely(!alignment.is_power_of_two()) {
+        return Err(libc::EINVAL);
+    }
+    if size == 0 {
+        return Ok(null_mut());
+    }
+    // Alignments below the largest scalar are always satisfied.
+    let alignment = cmp::max(alignment, mem::align_of::<libc::max_align_t>());
+    let layout = Layout::from_size_align(size, alignment).map_err(|_| libc::ENOMEM)?;
+    match COLLAM.alloc(layout).cast::<c_void>() {
+        ptr if ptr.is_null() => Err(libc::ENOMEM),
+        ptr => Ok(ptr),
+    }
+}
+
+#[inline]
+unsafe fn set_errno(errno: i32) {
+    *libc::__errno_location() = errno;
+}
+
+#[cfg_attr(not(test), no_mangle)]
 pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> usize {
     if ptr.is_null() {
         return 0;
@@ -69,23 +215,226 @@ pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> usize {
         Some(b) => b,
         None => return 0,
     };
-    if unlikely(!block.as_ref().verify()) {
-        eprintln!(
-            "malloc_usable_size(): Unable to verify {} at {:p}",
-            block.as_ref(),
-            block
-        );
+    if unlikely(!COLLAM.check_in_use(block, "malloc_usable_size")) {
         return 0;
     }
     block.size()
 }
 
-// TODO: implement me
-#[no_mangle]
+/// Returns free memory to the OS, keeping `pad` bytes at the top of the heap.
+/// Returns 1 if any memory has been released, 0 otherwise.
+#[cfg_attr(not(test), no_mangle)]
+pub extern "C" fn malloc_trim(pad: usize) -> i32 {
+    COLLAM.trim(pad) as i32
+}
+
+/// Returns a summary of the memory usage, see `mallinfo(3)`.
+/// Values exceeding `int` are truncated like in glibc, use `mallinfo2` instead.
+#[cfg_attr(not(test), no_mangle)]
+pub extern "C" fn mallinfo() -> libc::mallinfo {
+    let info = mallinfo2();
+    libc::mallinfo {
+        arena: info.arena as i32,
+        ordblks: info.ordblks as i32,
+        smblks: info.smblks as i32,
+        hblks: info.hblks as i32,
+        hblkhd: info.hblkhd as i32,
+        usmblks: info.usmblks as i32,
+        fsmblks: info.fsmblks as i32,
+        uordblks: info.uordblks as i32,
+        fordblks: info.fordblks as i32,
+        keepcost: info.keepcost as i32,
+    }
+}
+
+/// Returns a summary of the memory usage, see `mallinfo2(3)`.
+#[cfg_attr(not(test), no_mangle)]
+pub extern "C" fn mallinfo2() -> libc::mallinfo2 {
+    let info = COLLAM.info();
+    libc::mallinfo2 {
+        arena: info.arena,
+        ordblks: info.free_blocks,
+        // Small blocks are cached per thread instead of being kept in fastbins.
+        smblks: 0,
+        hblks: info.mmapped_blocks,
+        hblkhd: info.mmapped,
+        usmblks: 0,
+        fsmblks: 0,
+        uordblks: info.in_use,
+        fordblks: info.free,
+        keepcost: info.releasable,
+    }
+}
+
+/// Prints the memory usage of each arena and in total to stderr, see `malloc_stats(3)`.
+#[cfg_attr(not(test), no_mangle)]
+pub extern "C" fn malloc_stats() {
+    // Arenas beyond a lowered `M_ARENA_MAX` may still hold memory counted in the total.
+    for idx in 0..ARENA_LIMIT {
+        if let Some(info) = COLLAM.arena_info(idx).filter(|info| info.arena != 0) {
+            eprintln!("Arena {}:", idx);
+            eprintln!("system bytes     = {:>10}", info.arena);
+            eprintln!("in use bytes     = {:>10}", info.in_use);
+        }
+    }
+    let info = COLLAM.info();
+    eprintln!("Total (incl. mmap):");
+    eprintln!("system bytes     = {:>10}", info.arena + info.mmapped);
+    eprintln!("in use bytes     = {:>10}", info.in_use + info.mmapped);
+    eprintln!("max mmap regions = {:>10}", info.max_mmapped_blocks);
+    eprintln!("max mmap bytes   = {:>10}", info.max_mmapped);
+}
+
+/// Writes a summary of the memory usage as XML to `stream`, see `malloc_info(3)`.
+/// Returns 0 on success and -1 on errors with `errno` set.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn malloc_info(options: i32, stream: *mut libc::FILE) -> i32 {
+    if options != 0 {
+        set_errno(libc::EINVAL);
+        return -1;
+    }
+    match COLLAM.write_info(&mut FileWriter(stream)) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Writes a report on the fragmentation of the free memory as text to `stream`.
+/// Returns 0 on success and -1 on errors with `errno` set.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn collam_fragmentation(stream: *mut libc::FILE) -> i32 {
+    match COLLAM.fragmentation().write(&mut FileWriter(stream)) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Writes a report on the outstanding allocations tracked with `leak_check` to `stream`.
+/// Returns 0 on success and -1 on errors with `errno` set.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn collam_leak_report(stream: *mut libc::FILE) -> i32 {
+    match leaks::summary().write(&mut FileWriter(stream)) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Checks all quarantined blocks for writes after free and releases them,
+/// see `quarantine`. Done at exit as well.
+#[cfg_attr(not(test), no_mangle)]
+pub extern "C" fn collam_quarantine_flush() {
+    COLLAM.flush_quarantine();
+}
+
+/// Registers `handler` to be called as `handler(int kind, void *ptr)` on detected
+/// heap corruption, see `collam::alloc::ErrorKind` for the kinds. NULL removes it.
+#[cfg_attr(not(test), no_mangle)]
+pub extern "C" fn collam_set_error_handler(handler: Option<ErrorHandler>) {
+    COLLAM.config().set_error_handler(handler);
+}
+
+/// Writes the heap profile sampled with `prof_sample` to the file at `path`,
+/// see `collam::profile`. Returns 0 on success and -1 on errors.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn collam_prof_dump(path: *const libc::c_char) -> i32 {
+    if !path.is_null() && profile::dump(core::ffi::CStr::from_ptr(path).to_bytes()) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Sets the file statistics are appended to as JSON lines at exit and on the dump signal.
+/// A null pointer or empty path disables dumping. Returns 0 on success and -1 on errors.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn collam_stats_dump_path(path: *const libc::c_char) -> i32 {
+    let path = match path.is_null() {
+        true => &[],
+        false => core::ffi::CStr::from_ptr(path).to_bytes(),
+    };
+    if stats::set_dump_path(path) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Dumps the statistics whenever `signal` arrives. Returns 0 on success and -1 on errors.
+#[cfg_attr(not(test), no_mangle)]
+pub extern "C" fn collam_stats_dump_signal(signal: i32) -> i32 {
+    if stats::set_dump_signal(signal) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Appends the current statistics to the dump file. Returns 0 on success and -1 on errors.
+#[cfg_attr(not(test), no_mangle)]
+pub extern "C" fn collam_stats_dump() -> i32 {
+    if stats::dump(stats::DumpReason::Request) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Formatted output to a C `FILE` stream.
+struct FileWriter(*mut libc::FILE);
+
+impl fmt::Write for FileWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // The stream sets errno on failure.
+        match unsafe { libc::fwrite(s.as_ptr().cast(), 1, s.len(), self.0) } {
+            n if n == s.len() => Ok(()),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+/// Adjusts the allocator parameters, see `mallopt(3)`.
+/// Returns 1 on success and 0 for unsupported parameters or invalid values.
+#[cfg_attr(not(test), no_mangle)]
 pub extern "C" fn mallopt(param: i32, value: i32) -> i32 {
-    eprintln!(
-        "[mallopt] not implemented! (param={}, value={})",
-        param, value
-    );
-    return 1;
+    let config = COLLAM.config();
+    match param {
+        libc::M_MMAP_THRESHOLD if value >= 0 => config.set_mmap_threshold(value as usize),
+        // Negative values disable trimming like in glibc.
+        libc::M_TRIM_THRESHOLD if value < 0 => config.set_trim_threshold(usize::MAX),
+        libc::M_TRIM_THRESHOLD => config.set_trim_threshold(value as usize),
+        libc::M_TOP_PAD if value >= 0 => config.set_top_pad(value as usize),
+        libc::M_ARENA_MAX if value > 0 => config.set_arena_max(value as usize),
+        libc::M_PERTURB => config.set_perturb(value as u8),
+        libc::M_CHECK_ACTION => {
+            // Only the bits shared with glibc, backtraces are kept as configured.
+            let mask = CHECK_ACTION_PRINT | CHECK_ACTION_ABORT;
+            let backtrace = config.check_action() & CHECK_ACTION_BACKTRACE;
+            config.set_check_action((value as usize & mask) | backtrace)
+        }
+        _ => return 0,
+    }
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::block::BLOCK_META_SIZE;
+
+    #[test]
+    fn test_aligned_mmapped() {
+        unsafe {
+            // Sizes filling whole pages with the header leave no space for the alignment.
+            let size = 33 * *PAGE_SIZE - BLOCK_META_SIZE;
+            let mut ptr = valloc(size);
+            let mut memptr = null_mut();
+            assert_eq!(posix_memalign(&mut memptr, 4096, size), 0);
+            for ptr in [&mut ptr, &mut memptr].iter_mut() {
+                assert_eq!(**ptr as usize % *PAGE_SIZE, 0);
+                assert!(malloc_usable_size(**ptr) >= size);
+                intrinsics::volatile_set_memory(ptr.cast::<u8>(), 1, size);
+                free(**ptr);
+            }
+        }
+    }
 }
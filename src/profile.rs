@@ -0,0 +1,601 @@
+//! Sampling heap profiler. Roughly every `interval` allocated bytes, the call stack
+//! of an allocation is captured by walking the frame pointers and the allocation
+//! is recorded until it is freed. The bookkeeping never allocates itself.
+//!
+//! Profiles are written in the heap profile format of gperftools, which is read
+//! by `pprof` and `jeprof`. Sampling intervals are exponentially distributed,
+//! so the tools can estimate the actual usage with the `heap_v2` scaling.
+//! NOTE: Stacks are truncated at the first frame without frame pointer.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{ffi::c_void, fmt, mem, ptr, str};
+
+use crate::alloc::PAGE_SIZE;
+use crate::table::{Entry, Table};
+use crate::trace;
+use crate::util::FdWriter;
+
+/// Maximum number of frames captured per call stack.
+pub const MAX_DEPTH: usize = 32;
+/// Default mean number of bytes allocated between two samples.
+pub const INTERVAL_DEFAULT: usize = 512 * 1024;
+
+/// Frames of the profiler itself, which are not captured.
+const SKIP_FRAMES: usize = 1;
+/// Maximum distance of two consecutive frame pointers, larger distances end the walk.
+const MAX_FRAME_SIZE: usize = 1 << 20;
+
+/// Mean number of bytes between two samples, 0 if sampling is disabled.
+static INTERVAL: AtomicUsize = AtomicUsize::new(0);
+/// Last non-zero interval, which is reported in profiles.
+static PERIOD: AtomicUsize = AtomicUsize::new(INTERVAL_DEFAULT);
+static PROFILE: spin::Mutex<Profile> = spin::Mutex::new(Profile::new());
+static DUMP_PATH: spin::Mutex<DumpPath> = spin::Mutex::new(DumpPath::new());
+/// Set once the dump at exit has been registered successfully.
+static DUMP_AT_EXIT: spin::Once<bool> = spin::Once::new();
+
+#[thread_local]
+static mut SAMPLER: Sampler = Sampler::new();
+
+/// Call stack of sampled allocations with their accumulated sizes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stack {
+    /// Hash of the frames, never 0 for valid stacks.
+    hash: usize,
+    depth: usize,
+    frames: [usize; MAX_DEPTH],
+    /// Number of sampled allocations, which are still live.
+    pub live_count: usize,
+    /// Requested bytes of the sampled allocations, which are still live.
+    pub live_bytes: usize,
+    /// Number of all sampled allocations.
+    pub total_count: usize,
+    /// Requested bytes of all sampled allocations.
+    pub total_bytes: usize,
+}
+
+impl Stack {
+    /// Returns the return addresses of the call stack, innermost first.
+    pub fn frames(&self) -> &[usize] {
+        &self.frames[..self.depth]
+    }
+}
+
+impl Entry for Stack {
+    #[inline]
+    fn key(&self) -> usize {
+        self.hash
+    }
+}
+
+/// Live sampled allocation.
+#[derive(Clone, Copy, Debug, Default)]
+struct Sample {
+    ptr: usize,
+    size: usize,
+    /// Hash of the call stack.
+    stack: usize,
+}
+
+impl Entry for Sample {
+    #[inline]
+    fn key(&self) -> usize {
+        self.ptr
+    }
+}
+
+/// Live samples and the call stacks of all samples.
+struct Profile {
+    samples: Table<Sample>,
+    /// Stacks are kept once all their allocations have been freed for the totals.
+    stacks: Table<Stack>,
+}
+
+impl Profile {
+    const fn new() -> Self {
+        Profile {
+            samples: Table::new(),
+            stacks: Table::new(),
+        }
+    }
+
+    /// Records a sample. Returns `false` if the tables could not be grown.
+    fn add(&mut self, ptr: usize, size: usize, frames: &[usize]) -> bool {
+        let hash = hash(frames);
+        if !self.samples.insert(Sample {
+            ptr,
+            size,
+            stack: hash,
+        }) {
+            return false;
+        }
+        let stack = match self.stacks.get_mut(hash) {
+            Some(stack) => stack,
+            None => {
+                let mut stack = Stack {
+                    hash,
+                    depth: frames.len(),
+                    ..Stack::default()
+                };
+                stack.frames[..frames.len()].copy_from_slice(frames);
+                if !self.stacks.insert(stack) {
+                    self.samples.remove(ptr);
+                    return false;
+                }
+                self.stacks.get_mut(hash).expect("missing inserted stack")
+            }
+        };
+        stack.live_count += 1;
+        stack.live_bytes += size;
+        stack.total_count += 1;
+        stack.total_bytes += size;
+        true
+    }
+
+    /// Removes the sample at `ptr`, if any.
+    fn remove(&mut self, ptr: usize) {
+        if let Some(sample) = self.samples.remove(ptr) {
+            if let Some(stack) = self.stacks.get_mut(sample.stack) {
+                stack.live_count -= 1;
+                stack.live_bytes -= sample.size;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.samples.clear();
+        self.stacks.clear();
+    }
+}
+
+/// Returns a non-zero hash of the frames.
+fn hash(frames: &[usize]) -> usize {
+    // FNV-1a over the words, collisions merge the stacks.
+    let mut hash = 0xCBF2_9CE4_8422_2325_u64 as usize;
+    for frame in frames.iter().chain(Some(&frames.len())) {
+        hash = (hash ^ frame).wrapping_mul(0x0100_0000_01B3);
+    }
+    if hash == 0 {
+        1
+    } else {
+        hash
+    }
+}
+
+/// Per-thread state deciding which allocations are sampled.
+struct Sampler {
+    /// Bytes to be allocated until the next sample.
+    countdown: usize,
+    /// State of the random number generator, 0 if not seeded yet.
+    rng: u64,
+}
+
+impl Sampler {
+    const fn new() -> Self {
+        Sampler {
+            countdown: 0,
+            rng: 0,
+        }
+    }
+
+    fn seed(&mut self) {
+        let seed = trace::now() ^ (self as *mut Sampler as u64);
+        self.rng = seed | 1;
+    }
+
+    /// Returns the next random number using xorshift64*.
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Returns an exponentially distributed number of bytes with the given mean.
+    fn next_interval(&mut self, mean: usize) -> usize {
+        // Uniformly distributed in (0, 1].
+        let uniform = ((self.next_random() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+        let interval = -ln(uniform) * mean as f64;
+        if interval < 1.0 {
+            1
+        } else if interval >= usize::MAX as f64 {
+            usize::MAX
+        } else {
+            interval as usize
+        }
+    }
+}
+
+/// Returns the natural logarithm of the positive, finite `x`.
+fn ln(x: f64) -> f64 {
+    // x = m * 2^e with m in [1, 2), ln(m) = 2 * atanh((m - 1) / (m + 1)).
+    let bits = x.to_bits();
+    let exponent = ((bits >> 52) & 0x7FF) as i64 - 1023;
+    let mantissa = f64::from_bits((bits & ((1 << 52) - 1)) | (1023 << 52));
+    let z = (mantissa - 1.0) / (mantissa + 1.0);
+    let z2 = z * z;
+    let series = z * (1.0 + z2 * (1.0 / 3.0 + z2 * (1.0 / 5.0 + z2 * (1.0 / 7.0 + z2 / 9.0))));
+    exponent as f64 * core::f64::consts::LN_2 + 2.0 * series
+}
+
+/// NUL terminated path of the dump at exit.
+struct DumpPath {
+    buf: [u8; libc::PATH_MAX as usize],
+    /// Length of the path, 0 if no dump is written at exit.
+    len: usize,
+}
+
+impl DumpPath {
+    const fn new() -> Self {
+        DumpPath {
+            buf: [0; libc::PATH_MAX as usize],
+            len: 0,
+        }
+    }
+}
+
+/// Returns the mean number of bytes between two samples, 0 if sampling is disabled.
+#[inline]
+pub fn interval() -> usize {
+    INTERVAL.load(Ordering::Relaxed)
+}
+
+/// Returns `true` if allocations are sampled.
+#[inline]
+pub fn active() -> bool {
+    interval() != 0
+}
+
+/// Sets the mean number of bytes between two samples. 0 disables sampling,
+/// allocations sampled so far are still recorded until they are freed.
+pub fn set_interval(bytes: usize) {
+    if bytes != 0 {
+        PERIOD.store(bytes, Ordering::Relaxed);
+    }
+    INTERVAL.store(bytes, Ordering::Relaxed);
+}
+
+/// Forgets all samples and call stacks.
+/// NOTE: Must not be called while sampled allocations are live.
+pub fn reset() {
+    PROFILE.lock().clear();
+}
+
+/// Sets the file the profile is written to at exit. An empty path disables the dump.
+/// Returns `false` if the path is invalid or the dump could not be registered.
+pub fn set_dump_path(path: &[u8]) -> bool {
+    let mut dump_path = DUMP_PATH.lock();
+    if path.len() >= dump_path.buf.len() || path.contains(&0) {
+        return false;
+    }
+    dump_path.buf[..path.len()].copy_from_slice(path);
+    dump_path.buf[path.len()] = 0;
+    dump_path.len = path.len();
+    if path.is_empty() {
+        return true;
+    }
+    *DUMP_AT_EXIT.call_once(|| unsafe { libc::atexit(dump_at_exit) == 0 })
+}
+
+/// Decides whether the allocation of `size` bytes at `ptr` is sampled and records it.
+/// Must only be called if `active()`. Returns `true` if the allocation has been recorded.
+#[inline]
+pub fn sample(ptr: *mut u8, size: usize) -> bool {
+    let sampler = unsafe { &mut *ptr::addr_of_mut!(SAMPLER) };
+    if sampler.countdown > size {
+        sampler.countdown -= size;
+        return false;
+    }
+    record(sampler, ptr as usize, size)
+}
+
+/// Draws the next sampling point and records the allocation, if still due.
+#[inline(never)]
+fn record(sampler: &mut Sampler, ptr: usize, size: usize) -> bool {
+    let interval = interval();
+    if interval == 0 {
+        return false;
+    }
+    // The first point of a thread is drawn on its first allocation.
+    if sampler.rng == 0 {
+        sampler.seed();
+        sampler.countdown = sampler.next_interval(interval);
+        if sampler.countdown > size {
+            sampler.countdown -= size;
+            return false;
+        }
+    }
+    sampler.countdown = sampler.next_interval(interval);
+
+    let mut frames = [0; MAX_DEPTH];
+    let depth = unsafe { backtrace(&mut frames) };
+    PROFILE.lock().add(ptr, size, &frames[..depth])
+}
+
+/// Stops recording the sampled allocation at `ptr`.
+/// NOTE: Must be called before the memory is released, as it may be reused right away.
+pub fn release(ptr: *mut u8) {
+    PROFILE.lock().remove(ptr as usize);
+}
+
+/// Returns the frame pointer of the calling function.
+#[inline(always)]
+fn frame_pointer() -> usize {
+    let fp: usize;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags))
+    };
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        core::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags))
+    };
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        fp = 0;
+    }
+    fp
+}
+
+/// Captures the return addresses of the current call stack by walking the frame pointers.
+/// Frame records must be readable and ascending, otherwise the walk stops.
+/// Returns the number of captured frames.
+#[inline(never)]
+pub(crate) unsafe fn backtrace(frames: &mut [usize]) -> usize {
+    let word = mem::size_of::<usize>();
+    let mut fp = frame_pointer();
+    // The current frame is readable, pages of further frames are checked once.
+    let mut checked = fp & !(*PAGE_SIZE - 1);
+    let mut skip = SKIP_FRAMES;
+    let mut depth = 0;
+    while depth < frames.len() {
+        if fp == 0
+            || !fp.is_multiple_of(word)
+            || !readable(fp, &mut checked)
+            || !readable(fp + 2 * word - 1, &mut checked)
+        {
+            break;
+        }
+        let next = *(fp as *const usize);
+        let ret = *((fp + word) as *const usize);
+        if ret == 0 {
+            break;
+        }
+        if skip > 0 {
+            skip -= 1;
+        } else {
+            frames[depth] = ret;
+            depth += 1;
+        }
+        // The stack grows down, so callers have higher frame pointers.
+        if next <= fp || next - fp > MAX_FRAME_SIZE {
+            break;
+        }
+        fp = next;
+    }
+    depth
+}
+
+/// Returns `true` if the page of `addr` is mapped, `checked` caches the last mapped page.
+unsafe fn readable(addr: usize, checked: &mut usize) -> bool {
+    let page = addr & !(*PAGE_SIZE - 1);
+    if page == *checked {
+        return true;
+    }
+    // Fails with ENOMEM for unmapped memory, without touching it.
+    if libc::msync(page as *mut c_void, *PAGE_SIZE, libc::MS_ASYNC) != 0 {
+        return false;
+    }
+    *checked = page;
+    true
+}
+
+/// Writes the profile in the heap profile format of gperftools, followed by
+/// the memory mappings of the process used to symbolize the addresses.
+/// NOTE: The profile is not locked while writing, so `out` may allocate.
+/// Stacks recorded meanwhile may be missing.
+pub fn write(out: &mut dyn fmt::Write) -> fmt::Result {
+    let mut total = Stack::default();
+    let capacity = {
+        let profile = PROFILE.lock();
+        for stack in profile.stacks.iter() {
+            total.live_count += stack.live_count;
+            total.live_bytes += stack.live_bytes;
+            total.total_count += stack.total_count;
+            total.total_bytes += stack.total_bytes;
+        }
+        profile.stacks.capacity()
+    };
+    write!(out, "heap profile: ")?;
+    write_counts(out, &total)?;
+    writeln!(out, " @ heap_v2/{}", PERIOD.load(Ordering::Relaxed))?;
+    for idx in 0..capacity {
+        let stack = match PROFILE.lock().stacks.slot(idx) {
+            Some(stack) => stack,
+            None => continue,
+        };
+        write_counts(out, &stack)?;
+        write!(out, " @")?;
+        for frame in stack.frames() {
+            write!(out, " {:#018x}", frame)?;
+        }
+        writeln!(out)?;
+    }
+    writeln!(out, "\nMAPPED_LIBRARIES:")?;
+    write_maps(out)
+}
+
+fn write_counts(out: &mut dyn fmt::Write, stack: &Stack) -> fmt::Result {
+    write!(
+        out,
+        "{:>6}: {:>8} [{:>6}: {:>8}]",
+        stack.live_count, stack.live_bytes, stack.total_count, stack.total_bytes
+    )
+}
+
+/// Copies `/proc/self/maps` to `out`.
+fn write_maps(out: &mut dyn fmt::Write) -> fmt::Result {
+    let fd = unsafe { libc::open(b"/proc/self/maps\0".as_ptr().cast(), libc::O_RDONLY) };
+    if fd < 0 {
+        return Ok(());
+    }
+    let mut buf = [0u8; 512];
+    // Bytes of a character split by the previous read.
+    let mut carry = 0;
+    let result = loop {
+        let n = unsafe { libc::read(fd, buf[carry..].as_mut_ptr().cast(), buf.len() - carry) };
+        if n <= 0 {
+            break Ok(());
+        }
+        let len = carry + n as usize;
+        let valid = match str::from_utf8(&buf[..len]) {
+            Ok(s) => s.len(),
+            Err(err) => err.valid_up_to(),
+        };
+        if let Err(err) = out.write_str(unsafe { str::from_utf8_unchecked(&buf[..valid]) }) {
+            break Err(err);
+        }
+        carry = len - valid;
+        buf.copy_within(valid..len, 0);
+        if carry >= 4 {
+            // Not UTF-8 at all, skip the offending bytes.
+            carry = 0;
+        }
+    };
+    unsafe { libc::close(fd) };
+    result
+}
+
+/// Writes the profile to the file at `path`, which is truncated.
+/// Returns `false` if the path is invalid or the file could not be written.
+pub fn dump(path: &[u8]) -> bool {
+    let mut name = [0u8; libc::PATH_MAX as usize];
+    if path.is_empty() || path.len() >= name.len() || path.contains(&0) {
+        return false;
+    }
+    name[..path.len()].copy_from_slice(path);
+    dump_to(&name)
+}
+
+/// Writes the profile to the file at the NUL terminated `path`.
+fn dump_to(path: &[u8]) -> bool {
+    unsafe {
+        let fd = libc::open(
+            path.as_ptr().cast(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC,
+            0o644,
+        );
+        if fd < 0 {
+            return false;
+        }
+        let written = write(&mut FdWriter(fd)).is_ok();
+        libc::close(fd) == 0 && written
+    }
+}
+
+extern "C" fn dump_at_exit() {
+    let dump_path = DUMP_PATH.lock();
+    if dump_path.len != 0 && !dump_to(&dump_path.buf) {
+        let mut out = FdWriter(libc::STDERR_FILENO);
+        let _ = fmt::Write::write_str(&mut out, "[libcollam.so]: unable to write heap profile\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ln() {
+        for x in [1.0, 0.5, 0.1, 1e-3, 1e-9, 0.999_999].iter() {
+            let expected = std::primitive::f64::ln(*x);
+            assert!((ln(*x) - expected).abs() < 1e-4, "ln({})", x);
+        }
+    }
+
+    #[test]
+    fn test_next_interval() {
+        let mut sampler = Sampler::new();
+        sampler.seed();
+        let count = 10_000;
+        let sum: usize = (0..count).map(|_| sampler.next_interval(1000)).sum();
+        // The mean of exponentially distributed intervals matches roughly.
+        let mean = sum / count;
+        assert!(mean > 900 && mean < 1100, "mean={}", mean);
+    }
+
+    #[inline(never)]
+    fn nested(depth: usize, frames: &mut [usize]) -> usize {
+        if depth == 0 {
+            unsafe { backtrace(frames) }
+        } else {
+            // Keep the call from becoming a tail call.
+            let n = nested(depth - 1, frames);
+            core::hint::black_box(n)
+        }
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut frames = [0; MAX_DEPTH];
+        let depth = nested(4, &mut frames);
+        assert!(depth >= 4, "depth={}", depth);
+        assert!(frames[..depth].iter().all(|frame| *frame != 0));
+        // Recursive calls return to the same address.
+        assert_eq!(frames[1], frames[2]);
+        let mut short = [0; 2];
+        assert_eq!(nested(4, &mut short), 2);
+    }
+
+    #[test]
+    fn test_profile() {
+        let mut profile = Profile::new();
+        assert!(profile.add(0x1000, 64, &[1, 2, 3]));
+        assert!(profile.add(0x2000, 32, &[1, 2, 3]));
+        assert!(profile.add(0x3000, 16, &[4]));
+        profile.remove(0x1000);
+        profile.remove(0x4000);
+        let stack = *profile.stacks.get_mut(hash(&[1, 2, 3])).unwrap();
+        assert_eq!(stack.frames(), [1, 2, 3]);
+        assert_eq!((stack.live_count, stack.live_bytes), (1, 32));
+        assert_eq!((stack.total_count, stack.total_bytes), (2, 96));
+        assert_eq!(profile.samples.iter().count(), 2);
+        profile.clear();
+    }
+
+    #[test]
+    fn test_sample() {
+        reset();
+        set_interval(1);
+        let mut sampled = 0;
+        for idx in 1..=16 {
+            sampled += sample((idx * 0x1000) as *mut u8, 4096) as usize;
+        }
+        set_interval(0);
+        // With a mean of 1 byte every allocation of 4 KiB is sampled.
+        assert_eq!(sampled, 16);
+        release(0x1000 as *mut u8);
+
+        let mut out = std::string::String::new();
+        write(&mut out).unwrap();
+        reset();
+        assert!(
+            out.starts_with("heap profile:     15:    61440 [    16:    65536] @ heap_v2/1\n"),
+            "{}",
+            out
+        );
+        assert!(out.contains("] @ 0x"));
+        assert!(out.contains("\nMAPPED_LIBRARIES:\n"));
+        assert!(out.contains("[stack]"));
+    }
+
+    #[test]
+    fn test_dump() {
+        let path = std::format!("/tmp/collam-profile-{}.heap", std::process::id());
+        assert!(dump(path.as_bytes()));
+        let profile = std::fs::read_to_string(&path).expect("missing profile");
+        std::fs::remove_file(&path).expect("unable to remove profile");
+        assert!(profile.starts_with("heap profile: "));
+        assert!(!dump(b""));
+    }
+}
@@ -1,53 +1,412 @@
-use core::ffi::c_void;
-use core::ptr::Unique;
+//! Process wide allocation statistics shared by all `Collam` instances.
+//! The counters are updated with relaxed atomics and are always enabled.
+//! Snapshots can be appended as JSON to a file at exit and on a signal.
 
-use crate::alloc::block::BlockPtr;
-use libc_print::libc_eprintln;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{fmt, mem, ptr};
 
-static mut HEAP_LOW_ADDR: Option<Unique<c_void>> = None;
-static mut HEAP_HIGH_ADDR: Option<Unique<c_void>> = None;
+use crate::util::{self, StackWriter};
 
-static mut HEAP_HEAD: Option<BlockPtr> = None;
-static mut HEAP_TAIL: Option<BlockPtr> = None;
+static STATS: Counters = Counters::new();
 
-pub unsafe fn update_ends(head: Option<BlockPtr>, tail: Option<BlockPtr>) {
-    HEAP_HEAD = head;
-    HEAP_TAIL = tail;
+/// Maximum length of the dump file path in bytes, excluding the terminating NUL.
+pub const DUMP_PATH_MAX: usize = libc::PATH_MAX as usize - 1;
+
+static DUMP_PATH: DumpPath = DumpPath::new();
+/// Set once the dump at exit has been registered successfully.
+static DUMP_AT_EXIT: spin::Once<bool> = spin::Once::new();
+
+/// Occasion of a statistics dump, which is included in the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpReason {
+    Exit,
+    Signal,
+    Request,
 }
 
-/// Updates heap information.
-/// Should only be called with the current program break.
-pub unsafe fn update_heap_info(ptr: *mut c_void) {
-    if HEAP_LOW_ADDR.is_none() {
-        HEAP_LOW_ADDR = Unique::new(ptr);
+impl DumpReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            DumpReason::Exit => "exit",
+            DumpReason::Signal => "signal",
+            DumpReason::Request => "request",
+        }
     }
+}
+
+/// NUL terminated path of the dump file, which is read by signal handlers without locking.
+struct DumpPath {
+    buf: UnsafeCell<[u8; DUMP_PATH_MAX + 1]>,
+    /// Length of the path, 0 if dumping is disabled.
+    len: AtomicUsize,
+}
+
+unsafe impl Sync for DumpPath {}
 
-    match HEAP_HIGH_ADDR {
-        Some(addr) => {
-            if addr.as_ptr() < ptr {
-                HEAP_HIGH_ADDR = Unique::new(ptr)
-            }
+impl DumpPath {
+    const fn new() -> Self {
+        DumpPath {
+            buf: UnsafeCell::new([0; DUMP_PATH_MAX + 1]),
+            len: AtomicUsize::new(0),
         }
-        None => HEAP_HIGH_ADDR = Unique::new(ptr),
     }
 }
 
-pub unsafe fn print() {
-    if HEAP_HEAD.is_some() && HEAP_TAIL.is_some() {
-        let head = HEAP_HEAD.unwrap();
-        let tail = HEAP_TAIL.unwrap();
-        println!("[stats]: head: {} at\t{:p}", head.as_ref(), head);
-        println!("[stats]: tail: {} at\t{:p}", tail.as_ref(), tail);
-        println!(
-            "[stats]: difference between head and tail: {} bytes",
-            tail.as_ptr() as usize - head.as_ptr() as usize
-        );
+/// Point in time copy of the allocation statistics.
+/// Counters of different fields may be updated concurrently,
+/// so a snapshot is not guaranteed to be consistent across fields.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Number of successful allocations, including moves by `realloc`.
+    pub allocations: usize,
+    /// Number of freed blocks, including moves by `realloc`.
+    pub frees: usize,
+    /// Number of `realloc` calls.
+    pub reallocs: usize,
+    /// Sum of the sizes requested by all allocations.
+    pub requested_bytes: usize,
+    /// Sum of the usable sizes granted to all allocations.
+    pub granted_bytes: usize,
+    /// Usable bytes of all blocks currently in use.
+    pub in_use: usize,
+    /// Highest number of usable bytes in use at any time.
+    pub peak_in_use: usize,
+    /// Number of successful `sbrk` calls moving the program break.
+    pub sbrk_calls: usize,
+    /// Number of successful `mmap` and `mremap` calls.
+    pub mmap_calls: usize,
+    /// Number of blocks split into two.
+    pub splits: usize,
+    /// Number of blocks merged with their successor.
+    pub merges: usize,
+    /// Number of blocks passed to the allocator, which failed verification.
+    pub failed_verifies: usize,
+    /// Number of blocks passed to the allocator, which had already been freed.
+    pub double_frees: usize,
+}
+
+struct Counters {
+    allocations: AtomicUsize,
+    frees: AtomicUsize,
+    reallocs: AtomicUsize,
+    requested_bytes: AtomicUsize,
+    granted_bytes: AtomicUsize,
+    in_use: AtomicUsize,
+    peak_in_use: AtomicUsize,
+    sbrk_calls: AtomicUsize,
+    mmap_calls: AtomicUsize,
+    splits: AtomicUsize,
+    merges: AtomicUsize,
+    failed_verifies: AtomicUsize,
+    double_frees: AtomicUsize,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            allocations: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            reallocs: AtomicUsize::new(0),
+            requested_bytes: AtomicUsize::new(0),
+            granted_bytes: AtomicUsize::new(0),
+            in_use: AtomicUsize::new(0),
+            peak_in_use: AtomicUsize::new(0),
+            sbrk_calls: AtomicUsize::new(0),
+            mmap_calls: AtomicUsize::new(0),
+            splits: AtomicUsize::new(0),
+            merges: AtomicUsize::new(0),
+            failed_verifies: AtomicUsize::new(0),
+            double_frees: AtomicUsize::new(0),
+        }
     }
 
-    if HEAP_LOW_ADDR.is_some() && HEAP_HIGH_ADDR.is_some() {
-        println!(
-            "[stats]: total heap size: {} bytes\n",
-            HEAP_HIGH_ADDR.unwrap().as_ptr() as usize - HEAP_LOW_ADDR.unwrap().as_ptr() as usize
+    /// Adds `size` usable bytes to the bytes in use and updates the peak.
+    #[inline]
+    fn grow(&self, size: usize) {
+        let in_use = self.in_use.fetch_add(size, Ordering::Relaxed) + size;
+        // Avoid the write to the shared peak as long as it is not exceeded.
+        if in_use > self.peak_in_use.load(Ordering::Relaxed) {
+            self.peak_in_use.fetch_max(in_use, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns a copy of the current allocation statistics.
+pub fn snapshot() -> Snapshot {
+    let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+    Snapshot {
+        allocations: load(&STATS.allocations),
+        frees: load(&STATS.frees),
+        reallocs: load(&STATS.reallocs),
+        requested_bytes: load(&STATS.requested_bytes),
+        granted_bytes: load(&STATS.granted_bytes),
+        in_use: load(&STATS.in_use),
+        peak_in_use: load(&STATS.peak_in_use),
+        sbrk_calls: load(&STATS.sbrk_calls),
+        mmap_calls: load(&STATS.mmap_calls),
+        splits: load(&STATS.splits),
+        merges: load(&STATS.merges),
+        failed_verifies: load(&STATS.failed_verifies),
+        double_frees: load(&STATS.double_frees),
+    }
+}
+
+/// Sets the file snapshots are appended to as JSON, one object per line,
+/// at process exit and whenever the dump signal arrives. An empty path disables dumping.
+/// Returns `false` if the path is too long or contains NUL bytes.
+/// NOTE: Must not be called concurrently with itself or while a dump is written.
+pub fn set_dump_path(path: &[u8]) -> bool {
+    if path.len() > DUMP_PATH_MAX || path.contains(&0) {
+        return false;
+    }
+    DUMP_PATH.len.store(0, Ordering::Release);
+    if path.is_empty() {
+        return true;
+    }
+    unsafe {
+        let buf = &mut *DUMP_PATH.buf.get();
+        buf[..path.len()].copy_from_slice(path);
+        buf[path.len()] = 0;
+    }
+    DUMP_PATH.len.store(path.len(), Ordering::Release);
+    *DUMP_AT_EXIT.call_once(|| unsafe { libc::atexit(dump_at_exit) == 0 })
+}
+
+/// Installs a handler, which dumps a snapshot whenever `signal` arrives.
+/// Returns `false` if the handler could not be installed.
+pub fn set_dump_signal(signal: i32) -> bool {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = dump_on_signal as extern "C" fn(i32) as usize;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, ptr::null_mut()) == 0
+    }
+}
+
+/// Appends a snapshot as JSON line to the dump file, if one is set.
+/// Only async-signal-safe functions are called, nothing is allocated.
+/// Returns `true` if the snapshot has been written.
+pub fn dump(reason: DumpReason) -> bool {
+    let len = DUMP_PATH.len.load(Ordering::Acquire);
+    if len == 0 {
+        return false;
+    }
+    let mut out = StackWriter::<1024>::new();
+    if write_json(&mut out, &snapshot(), reason).is_err() {
+        return false;
+    }
+    unsafe {
+        let fd = libc::open(
+            DUMP_PATH.buf.get().cast(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND | libc::O_CLOEXEC,
+            0o644,
         );
+        if fd < 0 {
+            return false;
+        }
+        // A single write keeps lines of concurrent dumps intact.
+        let written = util::write_all(fd, out.as_bytes());
+        libc::close(fd);
+        written
+    }
+}
+
+extern "C" fn dump_at_exit() {
+    dump(DumpReason::Exit);
+}
+
+extern "C" fn dump_on_signal(_signal: i32) {
+    // The interrupted code may be about to inspect errno.
+    let errno = unsafe { *libc::__errno_location() };
+    dump(DumpReason::Signal);
+    unsafe { *libc::__errno_location() = errno };
+}
+
+/// Writes the snapshot as a single line JSON object, along with the reason,
+/// process id and wall-clock time in seconds.
+fn write_json(out: &mut dyn fmt::Write, stats: &Snapshot, reason: DumpReason) -> fmt::Result {
+    let mut now: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut now) };
+    write!(
+        out,
+        "{{\"reason\":\"{}\",\"pid\":{},\"time\":{}.{:03}",
+        reason.as_str(),
+        unsafe { libc::getpid() },
+        now.tv_sec,
+        now.tv_nsec / 1_000_000
+    )?;
+    let fields = [
+        ("allocations", stats.allocations),
+        ("frees", stats.frees),
+        ("reallocs", stats.reallocs),
+        ("requested_bytes", stats.requested_bytes),
+        ("granted_bytes", stats.granted_bytes),
+        ("in_use", stats.in_use),
+        ("peak_in_use", stats.peak_in_use),
+        ("sbrk_calls", stats.sbrk_calls),
+        ("mmap_calls", stats.mmap_calls),
+        ("splits", stats.splits),
+        ("merges", stats.merges),
+        ("failed_verifies", stats.failed_verifies),
+        ("double_frees", stats.double_frees),
+    ];
+    for (name, value) in fields.iter() {
+        write!(out, ",\"{}\":{}", name, value)?;
+    }
+    writeln!(out, "}}")
+}
+
+/// Records an allocation of `requested` bytes served by a block of `granted` bytes.
+#[inline]
+pub(crate) fn record_alloc(requested: usize, granted: usize) {
+    STATS.allocations.fetch_add(1, Ordering::Relaxed);
+    STATS
+        .requested_bytes
+        .fetch_add(requested, Ordering::Relaxed);
+    STATS.granted_bytes.fetch_add(granted, Ordering::Relaxed);
+    STATS.grow(granted);
+}
+
+/// Records the free of a block of `size` usable bytes.
+#[inline]
+pub(crate) fn record_free(size: usize) {
+    STATS.frees.fetch_add(1, Ordering::Relaxed);
+    STATS.in_use.fetch_sub(size, Ordering::Relaxed);
+}
+
+/// Records a `realloc` call.
+#[inline]
+pub(crate) fn record_realloc() {
+    STATS.reallocs.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records the in-place resize of a block in use from `old_size` to `new_size` usable bytes.
+#[inline]
+pub(crate) fn record_resize(old_size: usize, new_size: usize) {
+    if new_size > old_size {
+        STATS.grow(new_size - old_size);
+    } else {
+        STATS
+            .in_use
+            .fetch_sub(old_size - new_size, Ordering::Relaxed);
+    }
+}
+
+/// Records a successful `sbrk` call moving the program break.
+#[inline]
+pub(crate) fn record_sbrk() {
+    STATS.sbrk_calls.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records a successful `mmap` or `mremap` call.
+#[inline]
+pub(crate) fn record_mmap() {
+    STATS.mmap_calls.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records the split of a block.
+#[inline]
+pub(crate) fn record_split() {
+    STATS.splits.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records the merge of two blocks.
+#[inline]
+pub(crate) fn record_merge() {
+    STATS.merges.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records a block which failed verification.
+#[inline]
+pub(crate) fn record_failed_verify() {
+    STATS.failed_verifies.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records a block which has been passed to the allocator after it has been freed.
+#[inline]
+pub(crate) fn record_double_free() {
+    STATS.double_frees.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_peak() {
+        let counters = Counters::new();
+        counters.grow(100);
+        counters.grow(50);
+        counters.in_use.fetch_sub(120, Ordering::Relaxed);
+        counters.grow(20);
+        assert_eq!(counters.in_use.load(Ordering::Relaxed), 50);
+        assert_eq!(counters.peak_in_use.load(Ordering::Relaxed), 150);
+    }
+
+    /// Returns the contents of the file at `path` and removes it.
+    fn take_file(path: &str) -> std::string::String {
+        let contents = std::fs::read_to_string(path).expect("unable to read dump");
+        std::fs::remove_file(path).expect("unable to remove dump");
+        contents
+    }
+
+    #[test]
+    fn test_write_json() {
+        let stats = Snapshot {
+            allocations: 3,
+            failed_verifies: 1,
+            ..Snapshot::default()
+        };
+        let mut out = StackWriter::<1024>::new();
+        write_json(&mut out, &stats, DumpReason::Request).expect("unable to write json");
+        let json = core::str::from_utf8(out.as_bytes()).expect("invalid utf-8");
+        assert!(json.starts_with("{\"reason\":\"request\",\"pid\":"));
+        assert!(json.contains(",\"allocations\":3,\"frees\":0,"));
+        assert!(json.ends_with(",\"failed_verifies\":1,\"double_frees\":0}\n"));
+    }
+
+    #[test]
+    fn test_dump() {
+        let path = std::format!("/tmp/collam-stats-{}.json", std::process::id());
+        assert!(!set_dump_path(&[b'/'; DUMP_PATH_MAX + 1]));
+        assert!(!set_dump_path(b"/tmp/\0"));
+
+        assert!(set_dump_path(path.as_bytes()));
+        assert!(dump(DumpReason::Request));
+        assert!(set_dump_signal(libc::SIGUSR2));
+        unsafe { libc::raise(libc::SIGUSR2) };
+        let contents = take_file(&path);
+        let lines: std::vec::Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"reason\":\"request\""));
+        assert!(lines[1].starts_with("{\"reason\":\"signal\""));
+
+        assert!(set_dump_path(b""));
+        assert!(!dump(DumpReason::Request));
+        unsafe {
+            libc::signal(libc::SIGUSR2, libc::SIG_DFL);
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let before = snapshot();
+        record_alloc(10, 16);
+        record_split();
+        record_merge();
+        record_failed_verify();
+        record_double_free();
+        let after = snapshot();
+        // Other tests may allocate concurrently.
+        assert!(after.allocations > before.allocations);
+        assert!(after.requested_bytes >= before.requested_bytes + 10);
+        assert!(after.granted_bytes >= before.granted_bytes + 16);
+        assert!(after.peak_in_use >= after.in_use);
+        assert!(after.splits > before.splits && after.merges > before.merges);
+        assert!(after.failed_verifies > before.failed_verifies);
+        assert!(after.double_frees > before.double_frees);
+        record_free(16);
     }
 }
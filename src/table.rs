@@ -0,0 +1,230 @@
+//! Hash table for the bookkeeping of debugging facilities, which is mapped directly
+//! from the OS, so it can be used while allocating without allocating itself.
+
+use core::{cmp, mem, ptr, slice};
+
+/// Number of slots of a table once the first entry is inserted.
+const TABLE_MIN_CAPACITY: usize = 256;
+
+/// Entry of a `Table`, which is identified by a non-zero key.
+/// Slots holding the default value with key 0 are empty.
+pub trait Entry: Copy + Default {
+    fn key(&self) -> usize;
+}
+
+/// Open addressing hash table using linear probing.
+pub struct Table<T: Entry> {
+    slots: *mut T,
+    /// Number of slots, 0 or a power of two.
+    capacity: usize,
+    len: usize,
+}
+
+unsafe impl<T: Entry> Send for Table<T> {}
+
+impl<T: Entry> Table<T> {
+    pub const fn new() -> Self {
+        Table {
+            slots: ptr::null_mut(),
+            capacity: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the number of slots.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the entry in the slot with the given index, if any.
+    pub fn slot(&self, idx: usize) -> Option<T> {
+        self.slots()
+            .get(idx)
+            .filter(|entry| entry.key() != 0)
+            .copied()
+    }
+
+    /// Returns all entries in arbitrary order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.slots().iter().filter(|entry| entry.key() != 0)
+    }
+
+    fn slots(&self) -> &[T] {
+        match self.capacity {
+            0 => &[],
+            capacity => unsafe { slice::from_raw_parts(self.slots, capacity) },
+        }
+    }
+
+    fn slots_mut(&mut self) -> &mut [T] {
+        match self.capacity {
+            0 => &mut [],
+            capacity => unsafe { slice::from_raw_parts_mut(self.slots, capacity) },
+        }
+    }
+
+    /// Returns the preferred slot of `key`.
+    #[inline]
+    fn home(&self, key: usize) -> usize {
+        // Fibonacci hashing, the low bits of addresses are mostly zero.
+        (key.wrapping_mul(0x9E37_79B9_7F4A_7C15_u64 as usize) >> 16) & (self.capacity - 1)
+    }
+
+    /// Returns the slot holding `key`, if any.
+    fn find(&self, key: usize) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let mask = self.capacity - 1;
+        let mut idx = self.home(key);
+        loop {
+            match self.slots()[idx].key() {
+                0 => return None,
+                k if k == key => return Some(idx),
+                _ => idx = (idx + 1) & mask,
+            }
+        }
+    }
+
+    /// Returns the entry with the given key, if any.
+    pub fn get_mut(&mut self, key: usize) -> Option<&mut T> {
+        let idx = self.find(key)?;
+        Some(&mut self.slots_mut()[idx])
+    }
+
+    /// Adds the entry, replacing one with the same key.
+    /// Returns `false` if the table could not be grown.
+    pub fn insert(&mut self, entry: T) -> bool {
+        debug_assert_ne!(entry.key(), 0);
+        // Keep the load factor below 3/4.
+        if (self.len + 1) * 4 > self.capacity * 3 && !self.grow() {
+            return false;
+        }
+        let mask = self.capacity - 1;
+        let mut idx = self.home(entry.key());
+        let slots = self.slots_mut();
+        while slots[idx].key() != 0 && slots[idx].key() != entry.key() {
+            idx = (idx + 1) & mask;
+        }
+        let added = slots[idx].key() == 0;
+        slots[idx] = entry;
+        self.len += added as usize;
+        true
+    }
+
+    /// Removes and returns the entry with the given key, if any.
+    pub fn remove(&mut self, key: usize) -> Option<T> {
+        let idx = self.find(key)?;
+        let mask = self.capacity - 1;
+        let removed = self.slots()[idx];
+        // Shift back following entries of the probe sequence instead of leaving tombstones.
+        let mut hole = idx;
+        let mut next = (hole + 1) & mask;
+        while self.slots()[next].key() != 0 {
+            let home = self.home(self.slots()[next].key());
+            // Entries may only move towards their home slot.
+            if (next.wrapping_sub(home) & mask) >= (next.wrapping_sub(hole) & mask) {
+                let slots = self.slots_mut();
+                slots[hole] = slots[next];
+                hole = next;
+            }
+            next = (next + 1) & mask;
+        }
+        self.slots_mut()[hole] = T::default();
+        self.len -= 1;
+        Some(removed)
+    }
+
+    /// Doubles the capacity of the table. Returns `false` if the memory could not be mapped.
+    fn grow(&mut self) -> bool {
+        let capacity = cmp::max(self.capacity * 2, TABLE_MIN_CAPACITY);
+        let slots = match unsafe { map_slots(capacity) } {
+            Some(slots) => slots,
+            None => return false,
+        };
+        let mut old = mem::replace(
+            self,
+            Table {
+                slots,
+                capacity,
+                len: 0,
+            },
+        );
+        for entry in old.iter() {
+            self.insert(*entry);
+        }
+        old.clear();
+        true
+    }
+
+    /// Removes all entries and returns the memory of the table to the OS.
+    pub fn clear(&mut self) {
+        if self.capacity != 0 {
+            unsafe { libc::munmap(self.slots.cast(), self.capacity * mem::size_of::<T>()) };
+        }
+        *self = Table::new();
+    }
+}
+
+/// Maps zeroed memory for `capacity` slots. Not accounted in the allocator statistics.
+unsafe fn map_slots<T>(capacity: usize) -> Option<*mut T> {
+    let ptr = libc::mmap(
+        ptr::null_mut(),
+        capacity.checked_mul(mem::size_of::<T>())?,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        -1,
+        0,
+    );
+    match ptr {
+        libc::MAP_FAILED => None,
+        ptr => Some(ptr.cast()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    struct Pair(usize, usize);
+
+    impl Entry for Pair {
+        fn key(&self) -> usize {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_table() {
+        let mut table = Table::new();
+        assert_eq!(table.remove(0x10), None);
+        // Enough entries to grow the table and wrap probe sequences.
+        let count = TABLE_MIN_CAPACITY * 8;
+        for idx in 1..=count {
+            assert!(table.insert(Pair(idx * 16, idx)));
+        }
+        assert_eq!(table.len, count);
+        assert!(table.insert(Pair(16, 7)));
+        assert_eq!(table.len, count);
+        for idx in (2..=count).step_by(2) {
+            assert_eq!(table.remove(idx * 16), Some(Pair(idx * 16, idx)));
+        }
+        assert_eq!(table.remove(32), None);
+        assert_eq!(table.len, count / 2);
+        // Remaining entries are still found after shifting.
+        for idx in (3..=count).step_by(2) {
+            assert_eq!(table.get_mut(idx * 16), Some(&mut Pair(idx * 16, idx)));
+            assert_eq!(table.remove(idx * 16), Some(Pair(idx * 16, idx)));
+        }
+        assert_eq!(table.iter().count(), 1);
+        let slot = (0..table.capacity()).find_map(|idx| table.slot(idx));
+        assert_eq!(slot, Some(Pair(16, 7)));
+        table.get_mut(16).unwrap().1 = 8;
+        assert_eq!(table.remove(16), Some(Pair(16, 8)));
+        assert_eq!(table.len, 0);
+        table.clear();
+        assert_eq!(table.capacity, 0);
+    }
+}
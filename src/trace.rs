@@ -0,0 +1,372 @@
+//! Binary trace of the allocation calls of the C ABI.
+//! Records are collected in a per-thread buffer and appended to the trace file
+//! with raw `write` calls, so tracing never allocates itself.
+//!
+//! The file starts with `TRACE_MAGIC` followed by `TRACE_VERSION` and `RECORD_SIZE`
+//! as native endian `u32`s, followed by `Record`s in native endianness.
+//! Records of different threads are interleaved and have to be ordered by timestamp.
+
+use core::sync::atomic::{AtomicI32, Ordering};
+use core::{ffi::c_void, mem, ptr, slice};
+
+use crate::util;
+
+/// Magic bytes at the start of each trace file.
+pub const TRACE_MAGIC: [u8; 8] = *b"CLMTRACE";
+/// Version of the trace format.
+pub const TRACE_VERSION: u32 = 1;
+/// Size of the file header in bytes.
+pub const HEADER_SIZE: usize = TRACE_MAGIC.len() + 2 * mem::size_of::<u32>();
+/// Size of a single record in bytes.
+pub const RECORD_SIZE: usize = mem::size_of::<Record>();
+
+/// Number of records buffered per thread before they are written.
+const BUFFER_RECORDS: usize = 64;
+
+/// Descriptor of the trace file, -1 if tracing is disabled.
+static TRACE_FD: AtomicI32 = AtomicI32::new(-1);
+/// Set once the flush at exit has been registered successfully.
+static FLUSH_AT_EXIT: spin::Once<bool> = spin::Once::new();
+/// Key used to flush the buffer of a thread on exit, `None` if it could not be created.
+static FLUSH_KEY: spin::Once<Option<libc::pthread_key_t>> = spin::Once::new();
+
+#[thread_local]
+static mut BUFFER: Buffer = Buffer::new();
+
+/// Traced allocation call.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Malloc = 1,
+    Calloc = 2,
+    Realloc = 3,
+    Free = 4,
+    /// Any call of the aligned allocation family.
+    Memalign = 5,
+}
+
+impl Op {
+    /// Returns the operation with the given code, if valid.
+    pub fn from_u8(code: u8) -> Option<Op> {
+        match code {
+            1 => Some(Op::Malloc),
+            2 => Some(Op::Calloc),
+            3 => Some(Op::Realloc),
+            4 => Some(Op::Free),
+            5 => Some(Op::Memalign),
+            _ => None,
+        }
+    }
+}
+
+/// A single traced call as stored in the trace file.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Record {
+    /// Code of the `Op`.
+    pub op: u8,
+    _reserved: [u8; 3],
+    /// Kernel id of the calling thread.
+    pub tid: u32,
+    /// Monotonic time in nanoseconds. Taken before frees and reallocs
+    /// and after allocations, so reused addresses are ordered correctly.
+    pub time: u64,
+    /// Pointer passed to `free` and `realloc`, 0 otherwise.
+    pub ptr: u64,
+    /// Requested size, the total size for `calloc`.
+    pub size: u64,
+    /// Requested alignment, 0 for the default alignment.
+    pub align: u64,
+    /// Returned pointer, 0 for `free` and failed calls.
+    pub result: u64,
+}
+
+impl Record {
+    /// Returns the record stored in the given bytes or `None` if the length does not match.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Record> {
+        if bytes.len() != RECORD_SIZE {
+            return None;
+        }
+        Some(unsafe { ptr::read_unaligned(bytes.as_ptr().cast::<Record>()) })
+    }
+
+    /// Returns the bytes the record is stored as.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts((self as *const Record).cast::<u8>(), RECORD_SIZE) }
+    }
+}
+
+/// Per-thread buffer of records, which have not been written yet.
+struct Buffer {
+    records: [Record; BUFFER_RECORDS],
+    len: usize,
+    /// Kernel id of the thread, 0 if not looked up yet.
+    tid: u32,
+    /// Set while the buffer is modified, calls made meanwhile are not traced.
+    busy: bool,
+    /// Set if the flush on thread exit has been registered.
+    registered: bool,
+}
+
+impl Buffer {
+    const fn new() -> Self {
+        Buffer {
+            records: [Record {
+                op: 0,
+                _reserved: [0; 3],
+                tid: 0,
+                time: 0,
+                ptr: 0,
+                size: 0,
+                align: 0,
+                result: 0,
+            }; BUFFER_RECORDS],
+            len: 0,
+            tid: 0,
+            busy: false,
+            registered: false,
+        }
+    }
+
+    /// Writes all buffered records to the trace file.
+    unsafe fn flush(&mut self) {
+        let fd = TRACE_FD.load(Ordering::Relaxed);
+        if self.len != 0 && fd >= 0 {
+            let bytes =
+                slice::from_raw_parts(self.records.as_ptr().cast::<u8>(), self.len * RECORD_SIZE);
+            util::write_all(fd, bytes);
+        }
+        self.len = 0;
+    }
+}
+
+/// Returns `true` if tracing is enabled.
+#[inline]
+pub fn enabled() -> bool {
+    TRACE_FD.load(Ordering::Relaxed) >= 0
+}
+
+/// Starts tracing to the file at `path`, which is truncated. An empty path stops tracing.
+/// Returns `false` if the path is invalid or the file could not be opened.
+/// NOTE: Records buffered by other threads are only written once their buffer is full
+/// or on their exit, so must not be called concurrently with traced calls.
+pub fn set_path(path: &[u8]) -> bool {
+    unsafe {
+        let buffer = &mut *ptr::addr_of_mut!(BUFFER);
+        buffer.flush();
+        let old = TRACE_FD.swap(-1, Ordering::Relaxed);
+        if old >= 0 {
+            libc::close(old);
+        }
+        if path.is_empty() {
+            return true;
+        }
+
+        let mut name = [0u8; libc::PATH_MAX as usize];
+        if path.len() >= name.len() || path.contains(&0) {
+            return false;
+        }
+        name[..path.len()].copy_from_slice(path);
+        let fd = libc::open(
+            name.as_ptr().cast(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_APPEND | libc::O_CLOEXEC,
+            0o644,
+        );
+        if fd < 0 {
+            return false;
+        }
+        let mut header = [0u8; HEADER_SIZE];
+        header[..8].copy_from_slice(&TRACE_MAGIC);
+        header[8..12].copy_from_slice(&TRACE_VERSION.to_ne_bytes());
+        header[12..].copy_from_slice(&(RECORD_SIZE as u32).to_ne_bytes());
+        if !util::write_all(fd, &header) {
+            libc::close(fd);
+            return false;
+        }
+        TRACE_FD.store(fd, Ordering::Relaxed);
+        *FLUSH_AT_EXIT.call_once(|| libc::atexit(flush_at_exit) == 0)
+    }
+}
+
+/// Writes the records buffered by the current thread to the trace file.
+pub fn flush() {
+    unsafe {
+        let buffer = &mut *ptr::addr_of_mut!(BUFFER);
+        if !buffer.busy {
+            buffer.flush();
+        }
+    }
+}
+
+/// Returns the current monotonic time in nanoseconds.
+#[inline]
+pub fn now() -> u64 {
+    let mut time: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+/// Returns the current time for calls, which have to be recorded with their start time,
+/// or 0 if tracing is disabled.
+#[inline]
+pub fn start() -> u64 {
+    if enabled() {
+        now()
+    } else {
+        0
+    }
+}
+
+/// Records a call started at `time` or, if 0, finished right now,
+/// if tracing is enabled.
+#[inline]
+pub fn record(op: Op, time: u64, ptr: *mut c_void, size: usize, align: usize, result: *mut c_void) {
+    if enabled() {
+        let record = Record {
+            op: op as u8,
+            _reserved: [0; 3],
+            tid: 0,
+            time,
+            ptr: ptr as u64,
+            size: size as u64,
+            align: align as u64,
+            result: result as u64,
+        };
+        unsafe { append(record) };
+    }
+}
+
+/// Adds the record to the buffer of the current thread, filling in the thread id and
+/// the time if missing, and writes the buffer once it is full.
+unsafe fn append(mut record: Record) {
+    let buffer = &mut *ptr::addr_of_mut!(BUFFER);
+    // Registering the flush on thread exit may allocate.
+    if buffer.busy {
+        return;
+    }
+    buffer.busy = true;
+    if record.time == 0 {
+        record.time = now();
+    }
+    if !buffer.registered {
+        buffer.registered = register();
+    }
+    if buffer.tid == 0 {
+        buffer.tid = libc::gettid() as u32;
+    }
+    record.tid = buffer.tid;
+    buffer.records[buffer.len] = record;
+    buffer.len += 1;
+    if buffer.len == BUFFER_RECORDS {
+        buffer.flush();
+    }
+    buffer.busy = false;
+}
+
+/// Registers `destroy` to be called on exit of the current thread.
+/// Returns `false` if the key could not be created or set.
+unsafe fn register() -> bool {
+    let key = FLUSH_KEY.call_once(|| {
+        let mut key: libc::pthread_key_t = 0;
+        match libc::pthread_key_create(&mut key, Some(destroy)) {
+            0 => Some(key),
+            _ => None,
+        }
+    });
+    match key {
+        Some(key) => {
+            let buffer = ptr::addr_of_mut!(BUFFER).cast::<c_void>();
+            libc::pthread_setspecific(*key, buffer) == 0
+        }
+        None => false,
+    }
+}
+
+/// Flushes the buffer of an exiting thread.
+unsafe extern "C" fn destroy(buffer: *mut c_void) {
+    let buffer = &mut *buffer.cast::<Buffer>();
+    // Calls traced by later destructors register the flush again.
+    buffer.registered = false;
+    buffer.flush();
+}
+
+extern "C" fn flush_at_exit() {
+    flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_op_from_u8() {
+        for op in [Op::Malloc, Op::Calloc, Op::Realloc, Op::Free, Op::Memalign].iter() {
+            assert_eq!(Op::from_u8(*op as u8), Some(*op));
+        }
+        assert_eq!(Op::from_u8(0), None);
+        assert_eq!(Op::from_u8(6), None);
+    }
+
+    #[test]
+    fn test_record_bytes() {
+        assert_eq!(RECORD_SIZE, 48);
+        let record = Record {
+            op: Op::Realloc as u8,
+            tid: 7,
+            time: 42,
+            ptr: 0x1000,
+            size: 64,
+            result: 0x2000,
+            ..Record::default()
+        };
+        assert_eq!(Record::from_bytes(record.as_bytes()), Some(record));
+        assert_eq!(Record::from_bytes(&record.as_bytes()[1..]), None);
+    }
+
+    #[test]
+    fn test_trace() {
+        let path = std::format!("/tmp/collam-trace-{}.bin", std::process::id());
+        assert!(set_path(path.as_bytes()));
+        let time = now();
+        for idx in 0..BUFFER_RECORDS + 1 {
+            record(
+                Op::Malloc,
+                time,
+                ptr::null_mut(),
+                idx,
+                0,
+                0x1000 as *mut c_void,
+            );
+        }
+        // Full buffers are written right away.
+        let size = std::fs::metadata(&path).expect("missing trace").len() as usize;
+        assert_eq!(size, HEADER_SIZE + BUFFER_RECORDS * RECORD_SIZE);
+        record(
+            Op::Free,
+            now(),
+            0x1000 as *mut c_void,
+            0,
+            0,
+            ptr::null_mut(),
+        );
+        assert!(set_path(b""));
+        assert!(!enabled());
+
+        let trace = std::fs::read(&path).expect("unable to read trace");
+        std::fs::remove_file(&path).expect("unable to remove trace");
+        assert_eq!(trace[..8], TRACE_MAGIC);
+        assert_eq!(
+            trace.len(),
+            HEADER_SIZE + (BUFFER_RECORDS + 2) * RECORD_SIZE
+        );
+        let records: std::vec::Vec<Record> = trace[HEADER_SIZE..]
+            .chunks_exact(RECORD_SIZE)
+            .filter_map(Record::from_bytes)
+            .collect();
+        assert_eq!(records[3].size, 3);
+        assert_eq!(records[3].tid, unsafe { libc::gettid() } as u32);
+        let last = records.last().unwrap();
+        assert_eq!((Op::from_u8(last.op), last.ptr), (Some(Op::Free), 0x1000));
+        assert!(last.time >= time);
+    }
+}
@@ -1,10 +1,10 @@
-use core::alloc::{Layout, LayoutErr};
+use core::alloc::{Layout, LayoutError};
 use core::ffi::c_void;
+use core::fmt;
 use core::intrinsics::unlikely;
 use core::mem::align_of;
-use core::ptr::Unique;
+use core::ptr::{null_mut, Unique};
 
-#[cfg(feature = "stats")]
 use crate::stats;
 
 /// Wrapper for the kernel sbrk call.
@@ -15,11 +15,123 @@ pub unsafe fn sbrk(size: isize) -> Option<Unique<c_void>> {
     if unlikely(ptr == -1_isize as *mut c_void) {
         return None;
     }
-    #[cfg(feature = "stats")]
-    stats::update_heap_info(ptr);
+    if size != 0 {
+        stats::record_sbrk();
+    }
+    Unique::new(ptr)
+}
+
+/// Wrapper for an anonymous private `mmap` call with read and write access.
+#[inline]
+pub unsafe fn mmap(size: usize) -> Option<Unique<c_void>> {
+    let ptr = libc::mmap(
+        null_mut(),
+        size,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        -1,
+        0,
+    );
+    if unlikely(ptr == libc::MAP_FAILED) {
+        return None;
+    }
+    stats::record_mmap();
+    Unique::new(ptr)
+}
+
+/// Wrapper for the kernel munmap call.
+#[inline]
+pub unsafe fn munmap(ptr: Unique<c_void>, size: usize) -> bool {
+    libc::munmap(ptr.as_ptr(), size) == 0
+}
+
+/// Wrapper for the kernel mremap call, the mapping may be moved.
+#[inline]
+pub unsafe fn mremap(
+    ptr: Unique<c_void>,
+    old_size: usize,
+    new_size: usize,
+) -> Option<Unique<c_void>> {
+    let ptr = libc::mremap(ptr.as_ptr(), old_size, new_size, libc::MREMAP_MAYMOVE);
+    if unlikely(ptr == libc::MAP_FAILED) {
+        return None;
+    }
+    stats::record_mmap();
     Unique::new(ptr)
 }
 
+/// Wrapper for the kernel mprotect call, which makes the given pages inaccessible.
+#[inline]
+pub unsafe fn mprotect_none(ptr: Unique<c_void>, size: usize) -> bool {
+    libc::mprotect(ptr.as_ptr(), size, libc::PROT_NONE) == 0
+}
+
+/// Wrapper for `madvise(MADV_DONTNEED)`, the kernel may reclaim the given pages
+/// and subsequent accesses will see zeroed memory.
+#[inline]
+pub unsafe fn madvise_dontneed(ptr: Unique<c_void>, size: usize) -> bool {
+    libc::madvise(ptr.as_ptr(), size, libc::MADV_DONTNEED) == 0
+}
+
+/// Writes all of `buf` to the file descriptor, retrying on interrupts and partial writes.
+/// Only async-signal-safe functions are called. Returns `false` on errors.
+pub unsafe fn write_all(fd: i32, mut buf: &[u8]) -> bool {
+    while !buf.is_empty() {
+        match libc::write(fd, buf.as_ptr().cast(), buf.len()) {
+            n if n > 0 => buf = &buf[n as usize..],
+            -1 if *libc::__errno_location() == libc::EINTR => (),
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Formatted output to a file descriptor using raw `write` calls, which never allocates.
+pub struct FdWriter(pub i32);
+
+impl fmt::Write for FdWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match unsafe { write_all(self.0, s.as_bytes()) } {
+            true => Ok(()),
+            false => Err(fmt::Error),
+        }
+    }
+}
+
+/// Formatted output into a fixed size buffer on the stack, which never allocates.
+/// Output exceeding the buffer is reported as `fmt::Error`.
+pub struct StackWriter<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> StackWriter<N> {
+    pub const fn new() -> Self {
+        StackWriter {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Returns the bytes written so far.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> fmt::Write for StackWriter<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len.checked_add(s.len()).ok_or(fmt::Error)?;
+        if end > N {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
 /// Aligns passed value to be at lest the size of the
 /// largest scalar type `libc::max_align_t` and returns it.
 /// NOTE: not checked for overflows!
@@ -28,16 +140,24 @@ pub const fn align_scalar_unchecked(val: usize) -> usize {
     (val + align - 1) & !(align - 1)
 }
 
+/// Rounds up the given address to the next multiple of `align`,
+/// which must be a power of two.
+/// NOTE: not checked for overflows!
+#[inline]
+pub const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
 /// Returns a `Layout` padded to the largest
 /// possible scalar for the current architecture.
 #[inline]
-pub fn pad_to_scalar(size: usize) -> Result<Layout, LayoutErr> {
+pub fn pad_to_scalar(size: usize) -> Result<Layout, LayoutError> {
     Ok(Layout::from_size_align(size, align_of::<libc::max_align_t>())?.pad_to_align())
 }
 
 /// Returns a `Layout` padded to align.
 #[inline]
-pub fn pad_to_align(size: usize, align: usize) -> Result<Layout, LayoutErr> {
+pub fn pad_to_align(size: usize, align: usize) -> Result<Layout, LayoutError> {
     Ok(Layout::from_size_align(size, align)?.pad_to_align())
 }
 
@@ -53,6 +173,14 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_align_up() {
+        assert_eq!(align_up(0, 64), 0);
+        assert_eq!(align_up(1, 64), 64);
+        assert_eq!(align_up(64, 64), 64);
+        assert_eq!(align_up(4097, 4096), 8192);
+    }
+
     #[test]
     fn test_pad_to_align_ok() {
         let align = 4096;
@@ -64,7 +192,7 @@ mod tests {
 
     #[test]
     fn test_pad_to_align_err() {
-        assert!(pad_to_align(usize::max_value() - 12, 4096).is_err());
+        assert!(pad_to_align(usize::MAX - 12, 4096).is_err());
     }
 
     #[test]
@@ -78,7 +206,33 @@ mod tests {
 
     #[test]
     fn test_pad_to_scalar_err() {
-        assert!(pad_to_scalar(usize::max_value() - 14).is_err());
+        assert!(pad_to_scalar(usize::MAX - 14).is_err());
+    }
+
+    #[test]
+    fn test_stack_writer() {
+        use core::fmt::Write;
+
+        let mut out = StackWriter::<8>::new();
+        write!(out, "{}-{}", 12, 34).expect("unable to write");
+        assert_eq!(out.as_bytes(), b"12-34");
+        assert!(write!(out, "5678").is_err());
+        assert_eq!(out.as_bytes(), b"12-34");
+    }
+
+    #[test]
+    fn test_write_all() {
+        unsafe {
+            let mut fds = [0; 2];
+            assert_eq!(libc::pipe(fds.as_mut_ptr()), 0);
+            assert!(write_all(fds[1], b"collam"));
+            let mut buf = [0u8; 6];
+            assert_eq!(libc::read(fds[0], buf.as_mut_ptr().cast(), 6), 6);
+            assert_eq!(&buf, b"collam");
+            libc::close(fds[0]);
+            assert!(!write_all(-1, b"x"));
+            libc::close(fds[1]);
+        }
     }
 
     #[test]
@@ -86,10 +240,50 @@ mod tests {
         unsafe { assert!(sbrk(0).is_some()) };
     }
 
+    #[test]
+    fn test_mmap_munmap() {
+        unsafe {
+            let ptr = mmap(8192).expect("mmap failed");
+            ptr.as_ptr().cast::<u8>().write_bytes(0xAB, 8192);
+            assert!(munmap(ptr, 8192));
+        }
+    }
+
+    #[test]
+    fn test_mremap() {
+        unsafe {
+            let ptr = mmap(4096).expect("mmap failed");
+            ptr.as_ptr().cast::<u8>().write_bytes(0xAB, 4096);
+            let ptr = mremap(ptr, 4096, 1 << 20).expect("mremap failed");
+            assert_eq!(*ptr.as_ptr().cast::<u8>().add(4095), 0xAB);
+            ptr.as_ptr().cast::<u8>().write_bytes(0xCD, 1 << 20);
+            assert!(munmap(ptr, 1 << 20));
+        }
+    }
+
+    #[test]
+    fn test_madvise_dontneed() {
+        unsafe {
+            let ptr = mmap(8192).expect("mmap failed");
+            ptr.as_ptr().cast::<u8>().write_bytes(0xAB, 8192);
+            assert!(madvise_dontneed(ptr, 8192));
+            let mut vec = [0u8; 2];
+            assert_eq!(libc::mincore(ptr.as_ptr(), 8192, vec.as_mut_ptr()), 0);
+            assert_eq!(vec[0] & 1, 0);
+            assert_eq!(*ptr.as_ptr().cast::<u8>().add(4096), 0);
+            assert!(munmap(ptr, 8192));
+        }
+    }
+
+    #[test]
+    fn test_mmap_err() {
+        unsafe { assert!(mmap(usize::MAX).is_none()) };
+    }
+
     #[test]
     fn test_sbrk_err() {
         unsafe {
-            assert!(sbrk(isize::min_value()).is_none());
+            assert!(sbrk(isize::MIN).is_none());
         }
     }
 }
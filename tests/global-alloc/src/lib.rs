@@ -0,0 +1,2 @@
+//! Integration tests running std collections on top of `collam::Collam`
+//! registered as `#[global_allocator]`. See `tests/` in this crate.
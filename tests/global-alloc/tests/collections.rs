@@ -0,0 +1,101 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[global_allocator]
+static A: collam::Collam = collam::Collam::new();
+
+#[test]
+fn test_vec_push_and_shrink() {
+    let mut v = Vec::new();
+    for i in 0..100_000usize {
+        v.push(i);
+    }
+    assert_eq!(v.iter().sum::<usize>(), 99_999 * 100_000 / 2);
+    v.truncate(10);
+    v.shrink_to_fit();
+    assert_eq!(v, (0..10).collect::<Vec<usize>>());
+}
+
+#[test]
+fn test_string_growth() {
+    let mut s = String::new();
+    for i in 0..10_000 {
+        s.push_str(&i.to_string());
+    }
+    assert!(s.starts_with("0123456789101112"));
+    assert!(s.ends_with("9999"));
+}
+
+#[test]
+fn test_hash_map() {
+    let mut map = HashMap::new();
+    for i in 0..10_000u64 {
+        map.insert(i, format!("value-{}", i));
+    }
+    for i in (0..10_000u64).step_by(2) {
+        map.remove(&i);
+    }
+    assert_eq!(map.len(), 5_000);
+    assert_eq!(map.get(&4_999).map(String::as_str), Some("value-4999"));
+}
+
+#[test]
+fn test_btree_map_and_vec_deque() {
+    let mut map = BTreeMap::new();
+    let mut queue = VecDeque::new();
+    for i in 0..5_000u32 {
+        map.insert(
+            i.wrapping_mul(2_654_435_761),
+            vec![i as u8; (i % 64) as usize],
+        );
+        queue.push_front(Box::new(i));
+    }
+    assert_eq!(map.len(), 5_000);
+    assert_eq!(*queue.pop_back().unwrap(), 0);
+    assert_eq!(*queue.pop_front().unwrap(), 4_999);
+}
+
+#[test]
+fn test_large_allocation() {
+    let size = 64 * 1024 * 1024;
+    let mut v = vec![0u8; size];
+    v[size - 1] = 0xAB;
+    assert_eq!(v.iter().map(|b| *b as usize).sum::<usize>(), 0xAB);
+}
+
+#[test]
+fn test_threads() {
+    let shared = Arc::new(Mutex::new(Vec::new()));
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                let local: Vec<String> = (0..1_000).map(|i| format!("{}-{}", t, i)).collect();
+                shared.lock().unwrap().extend(local);
+            })
+        })
+        .collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+    assert_eq!(shared.lock().unwrap().len(), 8_000);
+}
+
+#[test]
+fn test_over_aligned_types() {
+    #[repr(align(64))]
+    struct CacheLine([u8; 64]);
+    #[repr(align(4096))]
+    struct Page([u8; 4096]);
+
+    let lines: Vec<CacheLine> = (0..1_000).map(|i| CacheLine([i as u8; 64])).collect();
+    assert_eq!(lines.as_ptr() as usize % 64, 0);
+    assert_eq!(lines[999].0[63], (999 % 256) as u8);
+
+    let pages: Vec<Box<Page>> = (0..16).map(|_| Box::new(Page([0xCD; 4096]))).collect();
+    for page in pages.iter() {
+        assert_eq!(&**page as *const Page as usize % 4096, 0);
+        assert_eq!(page.0[4095], 0xCD);
+    }
+}